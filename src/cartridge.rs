@@ -0,0 +1,440 @@
+use log::{debug, info};
+use std::{fs, io, path::PathBuf};
+
+use crate::cartridge_header::CartridgeHeader;
+use crate::cartridge_mbc1::Mbc1;
+use crate::cartridge_mbc2::{MBC2_RAM_SIZE, Mbc2};
+use crate::cartridge_mbc3::{Mbc3, RTC_SAVE_LENGTH, Rtc};
+use crate::cartridge_mbc5::Mbc5;
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+
+pub(crate) const ROM_BANK_SIZE: usize = 0x4000;
+pub(crate) const RAM_BANK_SIZE: usize = 0x2000;
+
+#[derive(Clone)]
+pub(crate) enum Mapper {
+    RomOnly,
+    Mbc1(Mbc1),
+    Mbc2(Mbc2),
+    Mbc3(Mbc3),
+    Mbc5(Mbc5),
+}
+
+/// Everything that lives on the cartridge: the ROM, any external RAM, and the mapper that
+/// decides which banks of those the cpu sees at 0x0000-0x7FFF and 0xA000-0xBFFF
+pub(crate) struct Cartridge {
+    pub(crate) header: CartridgeHeader,
+    pub(crate) rom: Vec<u8>,
+    pub(crate) ram: Vec<u8>,
+    pub(crate) mapper: Mapper,
+    pub(crate) has_battery: bool,
+    // where battery backed RAM gets persisted, None if the cart has no battery
+    pub(crate) save_path: Option<PathBuf>,
+    // RAM (or the clock) has been written since the last save
+    pub(crate) ram_dirty: bool,
+    // the game just disabled RAM after writing to it, which is a good hint it's done saving
+    pub(crate) flush_requested: bool,
+}
+
+impl Cartridge {
+    pub(crate) fn new(rom: Vec<u8>, header: CartridgeHeader) -> Result<Self, String> {
+        let cart_type = header.cartridge_type;
+        //only the battery backed types of the mappers below
+        let has_battery = matches!(cart_type, 0x03 | 0x06 | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E);
+        let mut ram_size = header.ram_size;
+        let mapper = match cart_type {
+            0x00 => Mapper::RomOnly,
+            0x01..=0x03 => Mapper::Mbc1(Mbc1::new(&rom)),
+            0x05 | 0x06 => {
+                ram_size = MBC2_RAM_SIZE;
+                Mapper::Mbc2(Mbc2::new())
+            }
+            0x0F | 0x10 => Mapper::Mbc3(Mbc3::new(true)),
+            0x11..=0x13 => Mapper::Mbc3(Mbc3::new(false)),
+            0x19..=0x1B => Mapper::Mbc5(Mbc5::new(false)),
+            0x1C..=0x1E => Mapper::Mbc5(Mbc5::new(true)),
+            other => return Err(format!("Unsupported cartridge type 0x{:02x}", other)),
+        };
+        info!(
+            "Cartridge is {} KiB ROM / {} KiB RAM",
+            rom.len() / 1024,
+            ram_size / 1024
+        );
+        Ok(Self {
+            header,
+            rom,
+            ram: vec![0u8; ram_size],
+            mapper,
+            has_battery,
+            save_path: None,
+            ram_dirty: false,
+            flush_requested: false,
+        })
+    }
+    pub(crate) fn rom_bank_count(&self) -> usize {
+        self.rom.len().div_ceil(ROM_BANK_SIZE)
+    }
+    /// Reads a byte from a bank, wrapping the bank number around the size of the ROM like the
+    /// unconnected upper address lines do on real carts
+    pub(crate) fn read_rom_bank(&self, bank: usize, address: u16) -> u8 {
+        let bank = bank % self.rom_bank_count();
+        let offset = (address as usize) & (ROM_BANK_SIZE - 1);
+        self.rom
+            .get(bank * ROM_BANK_SIZE + offset)
+            .copied()
+            .unwrap_or(0xFF)
+    }
+    /// Index into `ram` for a bank/address pair, or None if there's no RAM to index into
+    pub(crate) fn ram_index(&self, bank: usize, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        let offset = (address as usize) & (RAM_BANK_SIZE - 1);
+        Some((bank * RAM_BANK_SIZE + offset) % self.ram.len())
+    }
+    pub(crate) fn read_rom(&self, address: u16) -> u8 {
+        match &self.mapper {
+            Mapper::RomOnly => self.rom.get(address as usize).copied().unwrap_or(0xFF),
+            Mapper::Mbc1(mbc1) => {
+                let bank = if address < 0x4000 {
+                    mbc1.rom_bank_low_area()
+                } else {
+                    mbc1.rom_bank_high_area()
+                };
+                self.read_rom_bank(bank, address)
+            }
+            Mapper::Mbc2(mbc2) => {
+                let bank = if address < 0x4000 {
+                    0
+                } else {
+                    mbc2.rom_bank as usize
+                };
+                self.read_rom_bank(bank, address)
+            }
+            Mapper::Mbc3(mbc3) => {
+                let bank = if address < 0x4000 {
+                    0
+                } else {
+                    mbc3.rom_bank as usize
+                };
+                self.read_rom_bank(bank, address)
+            }
+            Mapper::Mbc5(mbc5) => {
+                let bank = if address < 0x4000 {
+                    0
+                } else {
+                    mbc5.rom_bank as usize
+                };
+                self.read_rom_bank(bank, address)
+            }
+        }
+    }
+    /// Writes to 0x0000-0x7FFF never reach the ROM, they poke at the mapper's registers
+    pub(crate) fn write_rom(&mut self, address: u16, value: u8) {
+        let ram_was_enabled = self.ram_enabled();
+        match &mut self.mapper {
+            Mapper::RomOnly => debug!("Ignoring write to ROM only cart at 0x{:04x}", address),
+            Mapper::Mbc1(mbc1) => mbc1.write_register(address, value),
+            Mapper::Mbc2(mbc2) => mbc2.write_register(address, value),
+            Mapper::Mbc3(mbc3) => mbc3.write_register(address, value),
+            Mapper::Mbc5(mbc5) => mbc5.write_register(address, value),
+        }
+        if ram_was_enabled && !self.ram_enabled() && self.ram_dirty {
+            self.flush_requested = true;
+        }
+    }
+    fn ram_enabled(&self) -> bool {
+        match &self.mapper {
+            Mapper::RomOnly => true,
+            Mapper::Mbc1(mbc1) => mbc1.ram_enabled,
+            Mapper::Mbc2(mbc2) => mbc2.ram_enabled,
+            Mapper::Mbc3(mbc3) => mbc3.ram_and_rtc_enabled,
+            Mapper::Mbc5(mbc5) => mbc5.ram_enabled,
+        }
+    }
+    pub(crate) fn read_ram(&self, address: u16) -> u8 {
+        let bank = match &self.mapper {
+            Mapper::RomOnly => 0,
+            Mapper::Mbc1(mbc1) => {
+                if !mbc1.ram_enabled {
+                    return 0xFF;
+                }
+                mbc1.ram_bank()
+            }
+            Mapper::Mbc2(mbc2) => {
+                if !mbc2.ram_enabled {
+                    return 0xFF;
+                }
+                //only the low nibble exists, the upper one floats high
+                return self.ram[Mbc2::ram_index(address)] | 0xF0;
+            }
+            Mapper::Mbc3(mbc3) => {
+                if !mbc3.ram_and_rtc_enabled {
+                    return 0xFF;
+                }
+                match mbc3.ram_bank() {
+                    Some(bank) => bank,
+                    None => return mbc3.read_rtc(),
+                }
+            }
+            Mapper::Mbc5(mbc5) => {
+                if !mbc5.ram_enabled {
+                    return 0xFF;
+                }
+                mbc5.ram_bank as usize
+            }
+        };
+        match self.ram_index(bank, address) {
+            Some(index) => self.ram[index],
+            None => 0xFF,
+        }
+    }
+    pub(crate) fn write_ram(&mut self, address: u16, value: u8) {
+        let bank = match &mut self.mapper {
+            Mapper::RomOnly => 0,
+            Mapper::Mbc1(mbc1) => {
+                if !mbc1.ram_enabled {
+                    return;
+                }
+                mbc1.ram_bank()
+            }
+            Mapper::Mbc2(mbc2) => {
+                if mbc2.ram_enabled {
+                    self.ram[Mbc2::ram_index(address)] = value & 0x0F;
+                    self.ram_dirty = true;
+                }
+                return;
+            }
+            Mapper::Mbc3(mbc3) => {
+                if !mbc3.ram_and_rtc_enabled {
+                    return;
+                }
+                match mbc3.ram_bank() {
+                    Some(bank) => bank,
+                    None => {
+                        mbc3.write_rtc(value);
+                        self.ram_dirty = true;
+                        return;
+                    }
+                }
+            }
+            Mapper::Mbc5(mbc5) => {
+                if !mbc5.ram_enabled {
+                    return;
+                }
+                mbc5.ram_bank as usize
+            }
+        };
+        if let Some(index) = self.ram_index(bank, address) {
+            self.ram[index] = value;
+            self.ram_dirty = true;
+        }
+    }
+    /// Returns the new rumble motor state if it changed since the last time this was called
+    pub(crate) fn take_rumble_event(&mut self) -> Option<bool> {
+        match &mut self.mapper {
+            Mapper::Mbc5(mbc5) if mbc5.rumble_changed => {
+                mbc5.rumble_changed = false;
+                Some(mbc5.rumble_active)
+            }
+            _ => None,
+        }
+    }
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        match &mut self.mapper {
+            Mapper::Mbc3(mbc3) => mbc3.rtc.as_mut(),
+            _ => None,
+        }
+    }
+    /// External RAM followed by the RTC footer (if there's a clock), in the same layout other
+    /// emulators use for their .sav files
+    pub(crate) fn save_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = self.rtc_mut() {
+            data.extend(rtc.save_bytes());
+        }
+        data
+    }
+    pub(crate) fn load_save_data(&mut self, data: &[u8]) {
+        let ram_length = self.ram.len().min(data.len());
+        self.ram[..ram_length].copy_from_slice(&data[..ram_length]);
+        let footer = &data[ram_length..];
+        if footer.is_empty() {
+            return;
+        }
+        if let Mapper::Mbc3(Mbc3 { rtc: Some(rtc), .. }) = &mut self.mapper {
+            match Rtc::from_save_bytes(footer) {
+                Some(saved_rtc) => *rtc = saved_rtc,
+                None => info!(
+                    "Ignoring {} byte RTC footer, expected {}",
+                    footer.len(),
+                    RTC_SAVE_LENGTH
+                ),
+            }
+        }
+    }
+    /// RAM (and the clock) in the same layout as a .sav, followed by the mapper registers
+    pub(crate) fn save_state(&mut self, writer: &mut StateWriter) {
+        let save_data = self.save_data();
+        writer.put_vec(&save_data);
+        match &self.mapper {
+            Mapper::RomOnly => writer.put_u8(0),
+            Mapper::Mbc1(mbc1) => {
+                writer.put_u8(1);
+                writer.put_bool(mbc1.ram_enabled);
+                writer.put_u8(mbc1.rom_bank_low);
+                writer.put_u8(mbc1.bank_high);
+                writer.put_bool(mbc1.advanced_banking_mode);
+            }
+            Mapper::Mbc2(mbc2) => {
+                writer.put_u8(2);
+                writer.put_bool(mbc2.ram_enabled);
+                writer.put_u8(mbc2.rom_bank);
+            }
+            Mapper::Mbc3(mbc3) => {
+                writer.put_u8(3);
+                writer.put_bool(mbc3.ram_and_rtc_enabled);
+                writer.put_u8(mbc3.rom_bank);
+                writer.put_u8(mbc3.ram_bank_or_rtc_register);
+                writer.put_bool(mbc3.latch_armed);
+            }
+            Mapper::Mbc5(mbc5) => {
+                writer.put_u8(5);
+                writer.put_bool(mbc5.ram_enabled);
+                writer.put_u16(mbc5.rom_bank);
+                writer.put_u8(mbc5.ram_bank);
+                writer.put_bool(mbc5.rumble_active);
+            }
+        }
+    }
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let save_data = reader.vec()?;
+        if save_data.len() < self.ram.len() {
+            return Err(SaveStateError::Corrupt(format!(
+                "{} bytes of cartridge RAM, expected {}",
+                save_data.len(),
+                self.ram.len()
+            )));
+        }
+        //ram_dirty is left as it was, so loading a state doesn't rewrite the .sav by itself.  The
+        //loaded RAM only gets persisted once the game writes to it, or on exit
+        self.load_save_data(&save_data);
+        let mapper_id = reader.u8()?;
+        match &mut self.mapper {
+            Mapper::RomOnly if mapper_id == 0 => (),
+            Mapper::Mbc1(mbc1) if mapper_id == 1 => {
+                mbc1.ram_enabled = reader.bool()?;
+                mbc1.rom_bank_low = reader.u8()?;
+                mbc1.bank_high = reader.u8()?;
+                mbc1.advanced_banking_mode = reader.bool()?;
+            }
+            Mapper::Mbc2(mbc2) if mapper_id == 2 => {
+                mbc2.ram_enabled = reader.bool()?;
+                mbc2.rom_bank = reader.u8()?;
+            }
+            Mapper::Mbc3(mbc3) if mapper_id == 3 => {
+                mbc3.ram_and_rtc_enabled = reader.bool()?;
+                mbc3.rom_bank = reader.u8()?;
+                mbc3.ram_bank_or_rtc_register = reader.u8()?;
+                mbc3.latch_armed = reader.bool()?;
+            }
+            Mapper::Mbc5(mbc5) if mapper_id == 5 => {
+                mbc5.ram_enabled = reader.bool()?;
+                mbc5.rom_bank = reader.u16()?;
+                mbc5.ram_bank = reader.u8()?;
+                let rumble_active = reader.bool()?;
+                if rumble_active != mbc5.rumble_active {
+                    mbc5.rumble_active = rumble_active;
+                    mbc5.rumble_changed = true;
+                }
+            }
+            _ => {
+                return Err(SaveStateError::Corrupt(format!(
+                    "Mapper {} doesn't match the cartridge",
+                    mapper_id
+                )));
+            }
+        }
+        Ok(())
+    }
+    /// Remembers where to persist battery backed RAM and loads whatever is already there.  Does
+    /// nothing for carts without a battery
+    pub(crate) fn attach_save_file(&mut self, path: PathBuf) -> io::Result<()> {
+        if !self.has_battery {
+            return Ok(());
+        }
+        match fs::read(&path) {
+            Ok(data) => {
+                info!("Loaded {} byte save from {}", data.len(), path.display());
+                self.load_save_data(&data);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No save at {}, starting fresh", path.display());
+            }
+            Err(e) => return Err(e),
+        }
+        self.save_path = Some(path);
+        Ok(())
+    }
+    pub(crate) fn write_save_file(&mut self) -> io::Result<()> {
+        let Some(path) = self.save_path.clone() else {
+            return Ok(());
+        };
+        let data = self.save_data();
+        fs::write(&path, data)?;
+        debug!("Wrote save to {}", path.display());
+        self.ram_dirty = false;
+        self.flush_requested = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A cart of the given type whose ROM banks each have their bank number at 0x2000 (low
+    /// byte) and 0x2001 (high byte) into the bank
+    pub(crate) fn test_cartridge(
+        cartridge_type: u8,
+        rom_banks: usize,
+        ram_size_code: u8,
+    ) -> Cartridge {
+        let mut rom = vec![0u8; rom_banks * ROM_BANK_SIZE];
+        for bank in 0..rom_banks {
+            rom[bank * ROM_BANK_SIZE + 0x2000] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 0x2001] = (bank >> 8) as u8;
+        }
+        rom[0x0147] = cartridge_type;
+        rom[0x0148] = (rom_banks / 2).trailing_zeros() as u8;
+        rom[0x0149] = ram_size_code;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        Cartridge::new(rom, header).unwrap()
+    }
+
+    /// Which bank 0x0000-0x3FFF (`high` false) or 0x4000-0x7FFF (`high` true) is showing
+    pub(crate) fn visible_bank(cartridge: &Cartridge, high: bool) -> usize {
+        let base = if high { 0x4000 } else { 0x0000 };
+        cartridge.read_rom(base + 0x2000) as usize
+            | (cartridge.read_rom(base + 0x2001) as usize) << 8
+    }
+
+    #[test]
+    fn battery_types() {
+        for cartridge_type in [0x03, 0x06, 0x0F, 0x10, 0x13, 0x1B, 0x1E] {
+            assert!(test_cartridge(cartridge_type, 4, 0x02).has_battery);
+        }
+        for cartridge_type in [0x00, 0x01, 0x02, 0x05, 0x11, 0x12, 0x19, 0x1A, 0x1C, 0x1D] {
+            assert!(!test_cartridge(cartridge_type, 4, 0x02).has_battery);
+        }
+    }
+
+    #[test]
+    fn rom_only_reads_flat() {
+        let cartridge = test_cartridge(0x00, 2, 0x00);
+        assert_eq!(visible_bank(&cartridge, false), 0);
+        assert_eq!(visible_bank(&cartridge, true), 1);
+        //and there's no RAM to read
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+    }
+}
@@ -0,0 +1,392 @@
+use std::fmt;
+
+use crate::cartridge::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const TITLE_LOCATION: usize = 0x0134;
+const MANUFACTURER_CODE_LOCATION: usize = 0x013F;
+const CGB_FLAG_LOCATION: usize = 0x0143;
+const NEW_LICENSEE_CODE_LOCATION: usize = 0x0144;
+const SGB_FLAG_LOCATION: usize = 0x0146;
+const CARTRIDGE_TYPE_LOCATION: usize = 0x0147;
+const ROM_SIZE_LOCATION: usize = 0x0148;
+const RAM_SIZE_LOCATION: usize = 0x0149;
+const DESTINATION_CODE_LOCATION: usize = 0x014A;
+const OLD_LICENSEE_CODE_LOCATION: usize = 0x014B;
+const VERSION_LOCATION: usize = 0x014C;
+const HEADER_CHECKSUM_LOCATION: usize = 0x014D;
+const GLOBAL_CHECKSUM_LOCATION: usize = 0x014E;
+const HEADER_END: usize = 0x0150;
+
+// old licensee code that means "go look at the new licensee code instead"
+const USE_NEW_LICENSEE_CODE: u8 = 0x33;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CgbSupport {
+    DmgOnly,
+    // works on both, with extra cgb features
+    Enhanced,
+    CgbOnly,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Destination {
+    Japan,
+    Overseas,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CartridgeHeader {
+    pub(crate) title: String,
+    // 4 character code, only on later carts
+    pub(crate) manufacturer_code: Option<String>,
+    pub(crate) cgb_support: CgbSupport,
+    pub(crate) sgb_support: bool,
+    pub(crate) old_licensee_code: u8,
+    // 2 character code, only used when old_licensee_code is 0x33
+    pub(crate) new_licensee_code: Option<String>,
+    pub(crate) cartridge_type: u8,
+    pub(crate) rom_size: usize,
+    pub(crate) ram_size: usize,
+    pub(crate) destination: Destination,
+    pub(crate) version: u8,
+    pub(crate) header_checksum: u8,
+    pub(crate) global_checksum: u16,
+}
+
+#[derive(Debug)]
+pub(crate) enum CartridgeHeaderError {
+    TooSmall(usize),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+    HeaderChecksumMismatch { expected: u8, actual: u8 },
+    GlobalChecksumMismatch { expected: u16, actual: u16 },
+}
+
+impl fmt::Display for CartridgeHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooSmall(length) => write!(
+                f,
+                "ROM is {} bytes, too small to contain a cartridge header",
+                length
+            ),
+            Self::UnknownRomSize(code) => write!(f, "Unknown ROM size code 0x{:02x}", code),
+            Self::UnknownRamSize(code) => write!(f, "Unknown RAM size code 0x{:02x}", code),
+            Self::HeaderChecksumMismatch { expected, actual } => write!(
+                f,
+                "Header checksum mismatch (header says 0x{:02x}, computed 0x{:02x})",
+                expected, actual
+            ),
+            Self::GlobalChecksumMismatch { expected, actual } => write!(
+                f,
+                "Global checksum mismatch (header says 0x{:04x}, computed 0x{:04x})",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeHeaderError {}
+
+/// Header strings are padded with 0s and older carts sometimes have junk past the title, so keep
+/// what's printable and stop at the first 0
+fn header_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| {
+            if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '?'
+            }
+        })
+        .collect::<String>()
+        .trim_end()
+        .to_owned()
+}
+
+impl CartridgeHeader {
+    pub(crate) fn parse(rom: &[u8]) -> Result<Self, CartridgeHeaderError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeHeaderError::TooSmall(rom.len()));
+        }
+        let cgb_flag = rom[CGB_FLAG_LOCATION];
+        let cgb_support = match cgb_flag {
+            0xC0 => CgbSupport::CgbOnly,
+            flag if (flag & 0x80) > 0 => CgbSupport::Enhanced,
+            _ => CgbSupport::DmgOnly,
+        };
+        //cgb era carts shrank the title to make room for the manufacturer code and cgb flag
+        let manufacturer_bytes = &rom[MANUFACTURER_CODE_LOCATION..CGB_FLAG_LOCATION];
+        let manufacturer_code = if cgb_support != CgbSupport::DmgOnly
+            && manufacturer_bytes
+                .iter()
+                .all(|byte| byte.is_ascii_uppercase())
+        {
+            Some(header_string(manufacturer_bytes))
+        } else {
+            None
+        };
+        let title_end = if manufacturer_code.is_some() {
+            MANUFACTURER_CODE_LOCATION
+        } else if cgb_support != CgbSupport::DmgOnly {
+            CGB_FLAG_LOCATION
+        } else {
+            CGB_FLAG_LOCATION + 1
+        };
+        let title = header_string(&rom[TITLE_LOCATION..title_end]);
+
+        let old_licensee_code = rom[OLD_LICENSEE_CODE_LOCATION];
+        let new_licensee_code = if old_licensee_code == USE_NEW_LICENSEE_CODE {
+            Some(header_string(
+                &rom[NEW_LICENSEE_CODE_LOCATION..NEW_LICENSEE_CODE_LOCATION + 2],
+            ))
+        } else {
+            None
+        };
+        //the sgb only looks at its flag if the old licensee says to use the new one
+        let sgb_support =
+            rom[SGB_FLAG_LOCATION] == 0x03 && old_licensee_code == USE_NEW_LICENSEE_CODE;
+
+        let rom_size = match rom[ROM_SIZE_LOCATION] {
+            code @ 0x00..=0x08 => (ROM_BANK_SIZE * 2) << code,
+            0x52 => ROM_BANK_SIZE * 72,
+            0x53 => ROM_BANK_SIZE * 80,
+            0x54 => ROM_BANK_SIZE * 96,
+            code => return Err(CartridgeHeaderError::UnknownRomSize(code)),
+        };
+        let ram_size = match rom[RAM_SIZE_LOCATION] {
+            0x00 => 0,
+            0x01 => 0x800, //unofficial 2KiB, only seen on homebrew
+            0x02 => RAM_BANK_SIZE,
+            0x03 => RAM_BANK_SIZE * 4,
+            0x04 => RAM_BANK_SIZE * 16,
+            0x05 => RAM_BANK_SIZE * 8,
+            code => return Err(CartridgeHeaderError::UnknownRamSize(code)),
+        };
+        let destination = if rom[DESTINATION_CODE_LOCATION] == 0 {
+            Destination::Japan
+        } else {
+            Destination::Overseas
+        };
+        Ok(Self {
+            title,
+            manufacturer_code,
+            cgb_support,
+            sgb_support,
+            old_licensee_code,
+            new_licensee_code,
+            cartridge_type: rom[CARTRIDGE_TYPE_LOCATION],
+            rom_size,
+            ram_size,
+            destination,
+            version: rom[VERSION_LOCATION],
+            header_checksum: rom[HEADER_CHECKSUM_LOCATION],
+            global_checksum: ((rom[GLOBAL_CHECKSUM_LOCATION] as u16) << 8)
+                | rom[GLOBAL_CHECKSUM_LOCATION + 1] as u16,
+        })
+    }
+    /// The boot ROM refuses to start a cart with a bad header checksum, so this failing almost
+    /// always means a bad dump
+    pub(crate) fn verify_header_checksum(&self, rom: &[u8]) -> Result<(), CartridgeHeaderError> {
+        let actual = rom[TITLE_LOCATION..HEADER_CHECKSUM_LOCATION]
+            .iter()
+            .fold(0u8, |checksum, byte| {
+                checksum.wrapping_sub(*byte).wrapping_sub(1)
+            });
+        if actual != self.header_checksum {
+            return Err(CartridgeHeaderError::HeaderChecksumMismatch {
+                expected: self.header_checksum,
+                actual,
+            });
+        }
+        Ok(())
+    }
+    /// Nothing on real hardware checks this one, and plenty of homebrew gets it wrong
+    pub(crate) fn verify_global_checksum(&self, rom: &[u8]) -> Result<(), CartridgeHeaderError> {
+        let actual = rom
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                *index != GLOBAL_CHECKSUM_LOCATION && *index != GLOBAL_CHECKSUM_LOCATION + 1
+            })
+            .fold(0u16, |checksum, (_, byte)| {
+                checksum.wrapping_add(*byte as u16)
+            });
+        if actual != self.global_checksum {
+            return Err(CartridgeHeaderError::GlobalChecksumMismatch {
+                expected: self.global_checksum,
+                actual,
+            });
+        }
+        Ok(())
+    }
+    pub(crate) fn licensee(&self) -> String {
+        match &self.new_licensee_code {
+            Some(code) => code.clone(),
+            None => format!("0x{:02x}", self.old_licensee_code),
+        }
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Game Title: {}", self.title)?;
+        if let Some(manufacturer_code) = &self.manufacturer_code {
+            writeln!(f, "Manufacturer Code: {}", manufacturer_code)?;
+        }
+        writeln!(f, "Licensee: {}", self.licensee())?;
+        writeln!(f, "Cartridge Type: 0x{:02x}", self.cartridge_type)?;
+        writeln!(f, "ROM Size: {} KiB", self.rom_size / 1024)?;
+        writeln!(f, "RAM Size: {} KiB", self.ram_size / 1024)?;
+        writeln!(f, "CGB Support: {:?}", self.cgb_support)?;
+        writeln!(f, "SGB Support: {}", self.sgb_support)?;
+        writeln!(
+            f,
+            "Cartridge Destination: {}",
+            match self.destination {
+                Destination::Japan => "Japan (or possibly overseas)",
+                Destination::Overseas => "Overseas Only",
+            }
+        )?;
+        write!(f, "Version: {}", self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank_rom() -> Vec<u8> {
+        vec![0u8; ROM_BANK_SIZE * 2]
+    }
+
+    fn with_title(rom: &mut [u8], title: &[u8]) {
+        rom[TITLE_LOCATION..TITLE_LOCATION + title.len()].copy_from_slice(title);
+    }
+
+    #[test]
+    fn dmg_title_uses_all_16_bytes() {
+        let mut rom = blank_rom();
+        with_title(&mut rom, b"ABCDEFGHIJKLMNOP");
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "ABCDEFGHIJKLMNOP");
+        assert_eq!(header.cgb_support, CgbSupport::DmgOnly);
+        assert_eq!(header.manufacturer_code, None);
+    }
+
+    #[test]
+    fn cgb_titles() {
+        let mut rom = blank_rom();
+        with_title(&mut rom, b"POKEMON\0\0\0\0AAXE");
+        rom[CGB_FLAG_LOCATION] = 0x80;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.cgb_support, CgbSupport::Enhanced);
+        assert_eq!(header.title, "POKEMON");
+        assert_eq!(header.manufacturer_code.as_deref(), Some("AAXE"));
+        //without an uppercase manufacturer code the title runs up to the cgb flag
+        with_title(&mut rom, b"LONG CGB TITLE1");
+        rom[CGB_FLAG_LOCATION] = 0xC0;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.cgb_support, CgbSupport::CgbOnly);
+        assert_eq!(header.title, "LONG CGB TITLE1");
+        assert_eq!(header.manufacturer_code, None);
+    }
+
+    #[test]
+    fn junk_in_the_title() {
+        let mut rom = blank_rom();
+        with_title(&mut rom, b"TETRIS\x01 \0XYZ");
+        assert_eq!(CartridgeHeader::parse(&rom).unwrap().title, "TETRIS?");
+    }
+
+    #[test]
+    fn licensee_and_sgb() {
+        let mut rom = blank_rom();
+        rom[SGB_FLAG_LOCATION] = 0x03;
+        rom[OLD_LICENSEE_CODE_LOCATION] = 0x01;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.licensee(), "0x01");
+        //the sgb flag only counts alongside the new licensee code
+        assert!(!header.sgb_support);
+        rom[OLD_LICENSEE_CODE_LOCATION] = USE_NEW_LICENSEE_CODE;
+        rom[NEW_LICENSEE_CODE_LOCATION..NEW_LICENSEE_CODE_LOCATION + 2].copy_from_slice(b"01");
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.licensee(), "01");
+        assert!(header.sgb_support);
+    }
+
+    #[test]
+    fn sizes() {
+        let mut rom = blank_rom();
+        for (code, size) in [(0x00, 0x8000), (0x05, 0x100000), (0x08, 0x800000)] {
+            rom[ROM_SIZE_LOCATION] = code;
+            assert_eq!(CartridgeHeader::parse(&rom).unwrap().rom_size, size);
+        }
+        rom[ROM_SIZE_LOCATION] = 0x52;
+        assert_eq!(CartridgeHeader::parse(&rom).unwrap().rom_size, 72 * 0x4000);
+        for (code, size) in [(0x00, 0), (0x02, 0x2000), (0x04, 0x20000), (0x05, 0x10000)] {
+            rom[RAM_SIZE_LOCATION] = code;
+            assert_eq!(CartridgeHeader::parse(&rom).unwrap().ram_size, size);
+        }
+    }
+
+    #[test]
+    fn bad_headers() {
+        let mut rom = blank_rom();
+        rom[ROM_SIZE_LOCATION] = 0x09;
+        assert!(matches!(
+            CartridgeHeader::parse(&rom),
+            Err(CartridgeHeaderError::UnknownRomSize(0x09))
+        ));
+        rom[ROM_SIZE_LOCATION] = 0x00;
+        rom[RAM_SIZE_LOCATION] = 0x06;
+        assert!(matches!(
+            CartridgeHeader::parse(&rom),
+            Err(CartridgeHeaderError::UnknownRamSize(0x06))
+        ));
+        assert!(matches!(
+            CartridgeHeader::parse(&rom[..HEADER_END - 1]),
+            Err(CartridgeHeaderError::TooSmall(0x14F))
+        ));
+    }
+
+    #[test]
+    fn header_checksum() {
+        let mut rom = blank_rom();
+        //25 zero bytes, each one taking off 1
+        rom[HEADER_CHECKSUM_LOCATION] = 0xE7;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(header.verify_header_checksum(&rom).is_ok());
+        rom[TITLE_LOCATION] = 0x01;
+        assert!(matches!(
+            header.verify_header_checksum(&rom),
+            Err(CartridgeHeaderError::HeaderChecksumMismatch {
+                expected: 0xE7,
+                actual: 0xE6
+            })
+        ));
+    }
+
+    #[test]
+    fn global_checksum() {
+        let mut rom = blank_rom();
+        rom[0x0000] = 0xFF;
+        rom[0x7FFF] = 0x02;
+        //the checksum bytes themselves aren't summed
+        rom[GLOBAL_CHECKSUM_LOCATION] = 0x01;
+        rom[GLOBAL_CHECKSUM_LOCATION + 1] = 0x01;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.global_checksum, 0x0101);
+        assert!(header.verify_global_checksum(&rom).is_ok());
+        rom[0x4000] = 0x01;
+        assert!(matches!(
+            header.verify_global_checksum(&rom),
+            Err(CartridgeHeaderError::GlobalChecksumMismatch {
+                expected: 0x0101,
+                actual: 0x0102
+            })
+        ));
+    }
+}
@@ -0,0 +1,233 @@
+use log::{debug, info};
+
+use crate::cartridge::ROM_BANK_SIZE;
+
+const LOGO_LOCATION: usize = 0x0104;
+const LOGO_LENGTH: usize = 0x30;
+const MULTICART_ROM_SIZE: usize = 0x100000;
+
+#[derive(Clone)]
+pub(crate) struct Mbc1 {
+    pub(crate) ram_enabled: bool,
+    // 0x2000-0x3FFF, 5 bits
+    pub(crate) rom_bank_low: u8,
+    // 0x4000-0x5FFF, 2 bits. upper ROM bank bits or RAM bank depending on mode
+    pub(crate) bank_high: u8,
+    // 0x6000-0x7FFF, mode 1 lets bank_high apply to 0x0000-0x3FFF and the RAM
+    pub(crate) advanced_banking_mode: bool,
+    // MBC1M wires only 4 bits of rom_bank_low, so bank_high sits one bit lower
+    pub(crate) multicart: bool,
+}
+
+impl Mbc1 {
+    pub(crate) fn new(rom: &[u8]) -> Self {
+        let multicart = Self::detect_multicart(rom);
+        if multicart {
+            info!("Detected MBC1M multicart");
+        }
+        Self {
+            ram_enabled: false,
+            rom_bank_low: 1,
+            bank_high: 0,
+            advanced_banking_mode: false,
+            multicart,
+        }
+    }
+    /// There's nothing in the header that says a cart is an MBC1M, but they're all 1MiB and each
+    /// game has its own header (and so its own copy of the logo) at the start of bank 0x10, 0x20
+    /// and 0x30.  Every ROM has the logo in bank 0, and a stray copy could turn up in a normal
+    /// game's data, so it takes at least two more
+    fn detect_multicart(rom: &[u8]) -> bool {
+        if rom.len() != MULTICART_ROM_SIZE {
+            return false;
+        }
+        let logo = &rom[LOGO_LOCATION..LOGO_LOCATION + LOGO_LENGTH];
+        let logo_count = [0x10usize, 0x20, 0x30]
+            .iter()
+            .filter(|bank| {
+                let location = *bank * ROM_BANK_SIZE + LOGO_LOCATION;
+                &rom[location..location + LOGO_LENGTH] == logo
+            })
+            .count();
+        logo_count >= 2
+    }
+    fn bank_high_shift(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+    pub(crate) fn rom_bank_low_area(&self) -> usize {
+        if self.advanced_banking_mode {
+            (self.bank_high << self.bank_high_shift()) as usize
+        } else {
+            0
+        }
+    }
+    pub(crate) fn rom_bank_high_area(&self) -> usize {
+        let low_mask = if self.multicart { 0b1111 } else { 0b11111 };
+        //rom_bank_low can never be 0 (see write_register), which is why 0x20/0x40/0x60 end up
+        //at 0x21/0x41/0x61 here
+        let low = self.rom_bank_low & low_mask;
+        ((self.bank_high << self.bank_high_shift()) | low) as usize
+    }
+    pub(crate) fn ram_bank(&self) -> usize {
+        if self.advanced_banking_mode {
+            self.bank_high as usize
+        } else {
+            0
+        }
+    }
+    pub(crate) fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = (value & 0b1111) == 0xA;
+                debug!("MBC1 RAM enabled: {}", self.ram_enabled);
+            }
+            0x2000..=0x3FFF => {
+                //the zero check happens on all 5 bits, even on multicarts that only use 4
+                let bank = value & 0b11111;
+                self.rom_bank_low = if bank == 0 { 1 } else { bank };
+                debug!("MBC1 ROM bank low: 0x{:02x}", self.rom_bank_low);
+            }
+            0x4000..=0x5FFF => {
+                self.bank_high = value & 0b11;
+                debug!("MBC1 bank high: 0x{:02x}", self.bank_high);
+            }
+            0x6000..=0x7FFF => {
+                self.advanced_banking_mode = (value & 0b1) > 0;
+                debug!("MBC1 banking mode: {}", self.advanced_banking_mode);
+            }
+            _ => panic!("MBC1 register write outside of ROM area 0x{:04x}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::tests::{test_cartridge, visible_bank};
+    use crate::cartridge::{Cartridge, Mapper};
+
+    // MBC1+RAM+BATTERY
+    const MBC1: u8 = 0x03;
+    // where each game's header starts on a 4 game multicart
+    const MULTICART_GAMES: &[usize] = &[0x00, 0x10, 0x20, 0x30];
+
+    /// A 1MiB cart with a logo at the start of each of `logo_banks`.  Everything else is 0xFF so
+    /// the blank logo areas don't match each other
+    fn one_mib_cartridge(logo_banks: &[usize]) -> Cartridge {
+        let mut cartridge = test_cartridge(MBC1, 64, 0x00);
+        for bank in 0..64 {
+            let logo = bank * ROM_BANK_SIZE + LOGO_LOCATION;
+            cartridge.rom[logo..logo + LOGO_LENGTH].fill(0xFF);
+        }
+        for game in logo_banks {
+            for index in 0..LOGO_LENGTH {
+                cartridge.rom[game * ROM_BANK_SIZE + LOGO_LOCATION + index] = 0xCE ^ index as u8;
+            }
+        }
+        cartridge.mapper = Mapper::Mbc1(Mbc1::new(&cartridge.rom));
+        cartridge
+    }
+
+    fn is_multicart(cartridge: &Cartridge) -> bool {
+        matches!(&cartridge.mapper, Mapper::Mbc1(mbc1) if mbc1.multicart)
+    }
+
+    #[test]
+    fn bank_0_selects_bank_1() {
+        let mut cartridge = test_cartridge(MBC1, 8, 0x00);
+        assert_eq!(visible_bank(&cartridge, true), 1);
+        cartridge.write_rom(0x2000, 0x05);
+        assert_eq!(visible_bank(&cartridge, true), 5);
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(visible_bank(&cartridge, true), 1);
+        assert_eq!(visible_bank(&cartridge, false), 0);
+    }
+
+    #[test]
+    fn banks_20_40_60_are_unreachable() {
+        let mut cartridge = test_cartridge(MBC1, 128, 0x00);
+        for bank_high in 1..=3u8 {
+            cartridge.write_rom(0x4000, bank_high);
+            cartridge.write_rom(0x2000, 0x00);
+            //the zero check only looks at the low 5 bits, so 0x20 becomes 0x21 and so on
+            assert_eq!(
+                visible_bank(&cartridge, true),
+                ((bank_high as usize) << 5) | 1
+            );
+        }
+    }
+
+    #[test]
+    fn mode_1_banks_the_low_area() {
+        let mut cartridge = test_cartridge(MBC1, 128, 0x00);
+        cartridge.write_rom(0x4000, 0x02);
+        cartridge.write_rom(0x2000, 0x03);
+        //mode 0 keeps bank 0 fixed
+        assert_eq!(visible_bank(&cartridge, false), 0);
+        assert_eq!(visible_bank(&cartridge, true), 0x43);
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(visible_bank(&cartridge, false), 0x40);
+        assert_eq!(visible_bank(&cartridge, true), 0x43);
+    }
+
+    #[test]
+    fn banks_wrap_around_the_rom() {
+        let mut cartridge = test_cartridge(MBC1, 16, 0x00);
+        cartridge.write_rom(0x2000, 0x13);
+        assert_eq!(visible_bank(&cartridge, true), 0x03);
+        //bank_high only reaches past the end of a 256KiB ROM
+        cartridge.write_rom(0x4000, 0x01);
+        assert_eq!(visible_bank(&cartridge, true), 0x03);
+    }
+
+    #[test]
+    fn ram_banking() {
+        let mut cartridge = test_cartridge(MBC1, 4, 0x03);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+        //only 0xA in the low nibble enables RAM
+        cartridge.write_rom(0x0000, 0x1A);
+        for bank in 0..4u8 {
+            cartridge.write_rom(0x4000, bank);
+            cartridge.write_ram(0xA000, 0x10 + bank);
+        }
+        //mode 0 only ever sees bank 0, so every write landed there
+        assert_eq!(cartridge.ram[0], 0x13);
+        cartridge.write_rom(0x6000, 0x01);
+        for bank in 0..4u8 {
+            cartridge.write_rom(0x4000, bank);
+            cartridge.write_ram(0xA000, 0x10 + bank);
+        }
+        cartridge.write_rom(0x4000, 0x02);
+        assert_eq!(cartridge.read_ram(0xA000), 0x12);
+        assert_eq!(cartridge.ram[3 * 0x2000], 0x13);
+        cartridge.write_rom(0x0000, 0x00);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn multicart_detection() {
+        assert!(is_multicart(&one_mib_cartridge(MULTICART_GAMES)));
+        //a normal 1MiB game only has the logo in bank 0
+        assert!(!is_multicart(&one_mib_cartridge(&[0x00])));
+        //and one stray copy isn't enough
+        assert!(!is_multicart(&one_mib_cartridge(&[0x00, 0x20])));
+        assert!(is_multicart(&one_mib_cartridge(&[0x00, 0x10, 0x20])));
+        //the logo copies only count on a 1MiB ROM
+        assert!(!is_multicart(&test_cartridge(MBC1, 32, 0x00)));
+    }
+
+    #[test]
+    fn multicart_banking() {
+        let mut cartridge = one_mib_cartridge(MULTICART_GAMES);
+        //bank_high sits one bit lower and only 4 bits of the low bank are wired
+        cartridge.write_rom(0x4000, 0x01);
+        cartridge.write_rom(0x2000, 0x12);
+        assert_eq!(visible_bank(&cartridge, true), 0x12);
+        cartridge.write_rom(0x6000, 0x01);
+        cartridge.write_rom(0x4000, 0x03);
+        assert_eq!(visible_bank(&cartridge, false), 0x30);
+        //the zero check still looks at all 5 bits, so 0x10 maps to bank 0 of the game
+        cartridge.write_rom(0x2000, 0x10);
+        assert_eq!(visible_bank(&cartridge, true), 0x30);
+    }
+}
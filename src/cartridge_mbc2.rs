@@ -0,0 +1,91 @@
+use log::debug;
+
+// 512 half-bytes of RAM built into the mapper itself, the header always says there's no RAM
+pub(crate) const MBC2_RAM_SIZE: usize = 0x200;
+
+#[derive(Clone)]
+pub(crate) struct Mbc2 {
+    pub(crate) ram_enabled: bool,
+    // 4 bits
+    pub(crate) rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub(crate) fn new() -> Self {
+        Self {
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+    /// The RAM only decodes the bottom 9 address bits, so it mirrors all over 0xA000-0xBFFF
+    pub(crate) fn ram_index(address: u16) -> usize {
+        (address as usize) & (MBC2_RAM_SIZE - 1)
+    }
+    pub(crate) fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            //both registers live in the same range, address bit 8 picks which one
+            0x0000..=0x3FFF => {
+                if (address & 0x100) == 0 {
+                    self.ram_enabled = (value & 0b1111) == 0xA;
+                    debug!("MBC2 RAM enabled: {}", self.ram_enabled);
+                } else {
+                    let bank = value & 0b1111;
+                    self.rom_bank = if bank == 0 { 1 } else { bank };
+                    debug!("MBC2 ROM bank: 0x{:02x}", self.rom_bank);
+                }
+            }
+            0x4000..=0x7FFF => (),
+            _ => panic!("MBC2 register write outside of ROM area 0x{:04x}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::tests::{test_cartridge, visible_bank};
+
+    // MBC2+BATTERY
+    const MBC2: u8 = 0x06;
+
+    #[test]
+    fn address_bit_8_picks_the_register() {
+        let mut cartridge = test_cartridge(MBC2, 16, 0x00);
+        //bit 8 clear is RAM enable, even in the 0x2000-0x3FFF range
+        cartridge.write_rom(0x2000, 0x05);
+        assert_eq!(visible_bank(&cartridge, true), 1);
+        //bit 8 set is the ROM bank, even in the 0x0000-0x1FFF range
+        cartridge.write_rom(0x0100, 0x05);
+        assert_eq!(visible_bank(&cartridge, true), 5);
+        cartridge.write_rom(0x2100, 0x1F);
+        assert_eq!(visible_bank(&cartridge, true), 0x0F);
+        cartridge.write_rom(0x2100, 0x10);
+        assert_eq!(visible_bank(&cartridge, true), 1);
+        cartridge.write_rom(0x2100, 0x0A);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+        cartridge.write_rom(0x3000, 0x0A);
+        cartridge.write_ram(0xA000, 0x00);
+        assert_eq!(cartridge.read_ram(0xA000), 0xF0);
+    }
+
+    #[test]
+    fn ram_is_half_bytes() {
+        let mut cartridge = test_cartridge(MBC2, 4, 0x00);
+        assert_eq!(cartridge.ram.len(), super::MBC2_RAM_SIZE);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0xA5);
+        assert_eq!(cartridge.ram[0], 0x05);
+        //the upper nibble isn't there, so it reads as 1s
+        assert_eq!(cartridge.read_ram(0xA000), 0xF5);
+    }
+
+    #[test]
+    fn ram_mirrors_every_512_bytes() {
+        let mut cartridge = test_cartridge(MBC2, 4, 0x00);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA1FF, 0x03);
+        assert_eq!(cartridge.read_ram(0xA3FF), 0xF3);
+        assert_eq!(cartridge.read_ram(0xBFFF), 0xF3);
+        cartridge.write_ram(0xBE00, 0x07);
+        assert_eq!(cartridge.read_ram(0xA000), 0xF7);
+    }
+}
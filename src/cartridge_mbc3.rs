@@ -0,0 +1,358 @@
+use log::debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// BGB/VBA-M append the clock to the end of the .sav: 5 live registers and 5 latched registers
+// as little endian u32s, followed by the unix timestamp they were saved at as a u64
+pub(crate) const RTC_SAVE_LENGTH: usize = 48;
+// some older saves only have a 32 bit timestamp
+const RTC_SAVE_LENGTH_SHORT: usize = 44;
+
+#[derive(Clone)]
+pub(crate) struct Mbc3 {
+    pub(crate) ram_and_rtc_enabled: bool,
+    // 0x2000-0x3FFF, 7 bits
+    pub(crate) rom_bank: u8,
+    // 0x4000-0x5FFF, 0x00-0x03 selects a RAM bank, 0x08-0x0C selects an RTC register
+    pub(crate) ram_bank_or_rtc_register: u8,
+    // latching takes a write of 0x00 followed by 0x01 to 0x6000-0x7FFF
+    pub(crate) latch_armed: bool,
+    pub(crate) rtc: Option<Rtc>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Rtc {
+    pub(crate) seconds: u8,
+    pub(crate) minutes: u8,
+    pub(crate) hours: u8,
+    // 9 bit day counter
+    pub(crate) days: u16,
+    pub(crate) halted: bool,
+    pub(crate) day_carry: bool,
+    // what the cpu actually reads, in register order 0x08-0x0C
+    pub(crate) latched: [u8; 5],
+    // host unix time the live registers were last brought up to date
+    pub(crate) last_update: u64,
+}
+
+fn host_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl Mbc3 {
+    pub(crate) fn new(has_rtc: bool) -> Self {
+        Self {
+            ram_and_rtc_enabled: false,
+            rom_bank: 1,
+            ram_bank_or_rtc_register: 0,
+            latch_armed: false,
+            rtc: if has_rtc { Some(Rtc::new()) } else { None },
+        }
+    }
+    /// RAM bank currently mapped at 0xA000-0xBFFF, or None if an RTC register is selected
+    pub(crate) fn ram_bank(&self) -> Option<usize> {
+        match self.ram_bank_or_rtc_register {
+            0x00..=0x03 => Some(self.ram_bank_or_rtc_register as usize),
+            _ => None,
+        }
+    }
+    pub(crate) fn read_rtc(&self) -> u8 {
+        match &self.rtc {
+            Some(rtc) => rtc.read_register(self.ram_bank_or_rtc_register),
+            None => 0xFF,
+        }
+    }
+    pub(crate) fn write_rtc(&mut self, value: u8) {
+        let register = self.ram_bank_or_rtc_register;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.write_register(register, value);
+        }
+    }
+    pub(crate) fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                self.ram_and_rtc_enabled = (value & 0b1111) == 0xA;
+                debug!("MBC3 RAM/RTC enabled: {}", self.ram_and_rtc_enabled);
+            }
+            0x2000..=0x3FFF => {
+                let bank = value & 0b1111111;
+                self.rom_bank = if bank == 0 { 1 } else { bank };
+                debug!("MBC3 ROM bank: 0x{:02x}", self.rom_bank);
+            }
+            0x4000..=0x5FFF => {
+                self.ram_bank_or_rtc_register = value;
+                debug!("MBC3 RAM bank/RTC register: 0x{:02x}", value);
+            }
+            0x6000..=0x7FFF => {
+                if self.latch_armed
+                    && value == 0x01
+                    && let Some(rtc) = &mut self.rtc
+                {
+                    rtc.latch();
+                }
+                self.latch_armed = value == 0x00;
+            }
+            _ => panic!("MBC3 register write outside of ROM area 0x{:04x}", address),
+        }
+    }
+}
+
+impl Rtc {
+    pub(crate) fn new() -> Self {
+        Self {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halted: false,
+            day_carry: false,
+            latched: [0u8; 5],
+            last_update: host_time(),
+        }
+    }
+    fn day_high_register(&self) -> u8 {
+        let day_high = ((self.days >> 8) & 0b1) as u8;
+        let halted = if self.halted { 0b01000000 } else { 0 };
+        let day_carry = if self.day_carry { 0b10000000 } else { 0 };
+        day_high | halted | day_carry
+    }
+    fn live_registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            (self.days & 0xFF) as u8,
+            self.day_high_register(),
+        ]
+    }
+    /// Catches the live registers up with the host clock
+    pub(crate) fn update(&mut self) {
+        let now = host_time();
+        let elapsed = now.saturating_sub(self.last_update);
+        self.last_update = now;
+        if self.halted || elapsed == 0 {
+            return;
+        }
+        let total_seconds = self.seconds as u64 + elapsed;
+        self.seconds = (total_seconds % 60) as u8;
+        let total_minutes = self.minutes as u64 + total_seconds / 60;
+        self.minutes = (total_minutes % 60) as u8;
+        let total_hours = self.hours as u64 + total_minutes / 60;
+        self.hours = (total_hours % 24) as u8;
+        let total_days = self.days as u64 + total_hours / 24;
+        if total_days > 0x1FF {
+            //stays set until the game clears it
+            self.day_carry = true;
+        }
+        self.days = (total_days % 0x200) as u16;
+    }
+    pub(crate) fn latch(&mut self) {
+        self.update();
+        self.latched = self.live_registers();
+        debug!("RTC latched {:?}", self.latched);
+    }
+    pub(crate) fn read_register(&self, register: u8) -> u8 {
+        match register {
+            0x08..=0x0C => self.latched[(register - 0x08) as usize],
+            _ => 0xFF,
+        }
+    }
+    pub(crate) fn write_register(&mut self, register: u8, value: u8) {
+        //bring the clock up to date first so the time before the write isn't lost (or counted
+        //when it shouldn't be, if this write is what halts it)
+        self.update();
+        match register {
+            0x08 => self.seconds = value & 0b111111,
+            0x09 => self.minutes = value & 0b111111,
+            0x0A => self.hours = value & 0b11111,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | (((value & 0b1) as u16) << 8);
+                self.halted = (value & 0b01000000) > 0;
+                self.day_carry = (value & 0b10000000) > 0;
+            }
+            _ => return,
+        }
+        //writes show up in the latched copy as well
+        self.latched[(register - 0x08) as usize] =
+            self.live_registers()[(register - 0x08) as usize];
+    }
+    pub(crate) fn save_bytes(&mut self) -> Vec<u8> {
+        self.update();
+        let mut bytes = Vec::with_capacity(RTC_SAVE_LENGTH);
+        for register in self.live_registers().iter().chain(self.latched.iter()) {
+            bytes.extend_from_slice(&(*register as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.last_update.to_le_bytes());
+        bytes
+    }
+    /// Restores the clock from a save footer and fast forwards it by however long we were closed
+    pub(crate) fn from_save_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RTC_SAVE_LENGTH && bytes.len() != RTC_SAVE_LENGTH_SHORT {
+            return None;
+        }
+        let register = |index: usize| {
+            let start = index * 4;
+            u32::from_le_bytes([
+                bytes[start],
+                bytes[start + 1],
+                bytes[start + 2],
+                bytes[start + 3],
+            ]) as u8
+        };
+        let mut timestamp_bytes = [0u8; 8];
+        let timestamp_length = bytes.len() - 40;
+        timestamp_bytes[..timestamp_length].copy_from_slice(&bytes[40..]);
+        let day_high = register(4);
+        let mut rtc = Self {
+            seconds: register(0),
+            minutes: register(1),
+            hours: register(2),
+            days: register(3) as u16 | (((day_high & 0b1) as u16) << 8),
+            halted: (day_high & 0b01000000) > 0,
+            day_carry: (day_high & 0b10000000) > 0,
+            latched: [
+                register(5),
+                register(6),
+                register(7),
+                register(8),
+                register(9),
+            ],
+            last_update: u64::from_le_bytes(timestamp_bytes),
+        };
+        rtc.update();
+        Some(rtc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::tests::{test_cartridge, visible_bank};
+
+    // MBC3+TIMER+RAM+BATTERY
+    const MBC3_RTC: u8 = 0x10;
+
+    /// A clock that doesn't move on its own, so nothing depends on the host clock
+    fn halted_rtc() -> Rtc {
+        let mut rtc = Rtc::new();
+        rtc.halted = true;
+        rtc
+    }
+
+    #[test]
+    fn rom_banking() {
+        let mut cartridge = test_cartridge(MBC3_RTC, 128, 0x03);
+        assert_eq!(visible_bank(&cartridge, true), 1);
+        //all 7 bits are wired, so there are no unreachable banks like on MBC1
+        for bank in [0x20, 0x40, 0x7F] {
+            cartridge.write_rom(0x2000, bank);
+            assert_eq!(visible_bank(&cartridge, true), bank as usize);
+        }
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(visible_bank(&cartridge, true), 1);
+        assert_eq!(visible_bank(&cartridge, false), 0);
+    }
+
+    #[test]
+    fn ram_and_rtc_share_the_window() {
+        let mut cartridge = test_cartridge(MBC3_RTC, 4, 0x03);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x02);
+        cartridge.write_ram(0xA000, 0x12);
+        assert_eq!(cartridge.ram[2 * 0x2000], 0x12);
+        cartridge.write_rom(0x4000, 0x08);
+        cartridge.write_ram(0xA000, 0x2A);
+        assert_eq!(cartridge.read_ram(0xA000), 0x2A);
+        //the seconds write didn't touch RAM
+        cartridge.write_rom(0x4000, 0x00);
+        assert_eq!(cartridge.read_ram(0xA000), 0x00);
+        cartridge.write_rom(0x0000, 0x00);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn latch_needs_0_then_1() {
+        let mut mbc3 = Mbc3::new(true);
+        mbc3.rtc = Some(halted_rtc());
+        mbc3.write_register(0x4000, 0x08);
+        mbc3.rtc.as_mut().unwrap().seconds = 5;
+        mbc3.write_register(0x6000, 0x01);
+        assert_eq!(mbc3.read_rtc(), 0);
+        mbc3.write_register(0x6000, 0x00);
+        mbc3.write_register(0x6000, 0x01);
+        assert_eq!(mbc3.read_rtc(), 5);
+        //and the latched copy stays put until the next latch
+        mbc3.rtc.as_mut().unwrap().seconds = 6;
+        assert_eq!(mbc3.read_rtc(), 5);
+    }
+
+    #[test]
+    fn writes_show_up_latched() {
+        let mut rtc = halted_rtc();
+        rtc.write_register(0x0A, 0xFF);
+        assert_eq!(rtc.read_register(0x0A), 0b11111);
+        rtc.write_register(0x0B, 0x34);
+        rtc.write_register(0x0C, 0b11000001);
+        assert_eq!(rtc.days, 0x134);
+        assert!(rtc.halted);
+        assert!(rtc.day_carry);
+        assert_eq!(rtc.read_register(0x0C), 0b11000001);
+        assert_eq!(rtc.read_register(0x0D), 0xFF);
+    }
+
+    #[test]
+    fn rollover_sets_day_carry() {
+        let mut rtc = Rtc::new();
+        rtc.minutes = 59;
+        rtc.hours = 23;
+        rtc.days = 0x1FF;
+        rtc.last_update = host_time() - 90;
+        rtc.update();
+        //the host clock may tick over a second while the test runs
+        assert!((30..=31).contains(&rtc.seconds));
+        assert_eq!(rtc.minutes, 0);
+        assert_eq!(rtc.hours, 0);
+        assert_eq!(rtc.days, 0);
+        assert!(rtc.day_carry);
+    }
+
+    #[test]
+    fn halted_rtc_doesnt_tick() {
+        let mut rtc = halted_rtc();
+        rtc.last_update = host_time() - 1000;
+        rtc.update();
+        assert_eq!(rtc.seconds, 0);
+        assert_eq!(rtc.minutes, 0);
+    }
+
+    #[test]
+    fn save_bytes_round_trip() {
+        let mut rtc = halted_rtc();
+        rtc.seconds = 1;
+        rtc.minutes = 2;
+        rtc.hours = 3;
+        rtc.days = 0x104;
+        rtc.latched = [5, 6, 7, 8, 9];
+        let bytes = rtc.save_bytes();
+        assert_eq!(bytes.len(), RTC_SAVE_LENGTH);
+        for bytes in [&bytes[..], &bytes[..RTC_SAVE_LENGTH_SHORT]] {
+            let loaded = Rtc::from_save_bytes(bytes).unwrap();
+            assert_eq!(loaded.live_registers(), rtc.live_registers());
+            assert_eq!(loaded.latched, rtc.latched);
+        }
+        assert!(Rtc::from_save_bytes(&bytes[..40]).is_none());
+    }
+
+    #[test]
+    fn loading_catches_up() {
+        let mut bytes = Rtc::new().save_bytes();
+        //saved two minutes ago
+        bytes[40..].copy_from_slice(&(host_time() - 120).to_le_bytes());
+        let loaded = Rtc::from_save_bytes(&bytes).unwrap();
+        assert_eq!(loaded.minutes, 2);
+        assert_eq!(loaded.hours, 0);
+    }
+}
@@ -0,0 +1,129 @@
+use log::debug;
+
+#[derive(Clone)]
+pub(crate) struct Mbc5 {
+    pub(crate) ram_enabled: bool,
+    // 0x2000-0x2FFF holds the low 8 bits, 0x3000-0x3FFF the 9th.  Unlike the older mappers
+    // bank 0 really is bank 0
+    pub(crate) rom_bank: u16,
+    // 0x4000-0x5FFF, 4 bits (3 on rumble carts, bit 3 drives the motor instead)
+    pub(crate) ram_bank: u8,
+    pub(crate) has_rumble: bool,
+    pub(crate) rumble_active: bool,
+    // set whenever the motor changes state, cleared once the frontend has seen it
+    pub(crate) rumble_changed: bool,
+}
+
+impl Mbc5 {
+    pub(crate) fn new(has_rumble: bool) -> Self {
+        Self {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble_active: false,
+            rumble_changed: false,
+        }
+    }
+    pub(crate) fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                //MBC5 wants exactly 0x0A, not just the low nibble
+                self.ram_enabled = value == 0x0A;
+                debug!("MBC5 RAM enabled: {}", self.ram_enabled);
+            }
+            0x2000..=0x2FFF => {
+                self.rom_bank = (self.rom_bank & 0x100) | value as u16;
+                debug!("MBC5 ROM bank: 0x{:03x}", self.rom_bank);
+            }
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | (((value & 0b1) as u16) << 8);
+                debug!("MBC5 ROM bank: 0x{:03x}", self.rom_bank);
+            }
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.ram_bank = value & 0b111;
+                    let rumble_active = (value & 0b1000) > 0;
+                    if rumble_active != self.rumble_active {
+                        self.rumble_active = rumble_active;
+                        self.rumble_changed = true;
+                    }
+                } else {
+                    self.ram_bank = value & 0b1111;
+                }
+                debug!("MBC5 RAM bank: 0x{:02x}", self.ram_bank);
+            }
+            0x6000..=0x7FFF => (),
+            _ => panic!("MBC5 register write outside of ROM area 0x{:04x}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::tests::{test_cartridge, visible_bank};
+
+    // MBC5+RAM+BATTERY
+    const MBC5: u8 = 0x1B;
+    // MBC5+RUMBLE+RAM+BATTERY
+    const MBC5_RUMBLE: u8 = 0x1E;
+
+    #[test]
+    fn nine_bit_rom_bank() {
+        let mut cartridge = test_cartridge(MBC5, 512, 0x00);
+        assert_eq!(visible_bank(&cartridge, true), 1);
+        cartridge.write_rom(0x2000, 0x23);
+        cartridge.write_rom(0x3000, 0x01);
+        assert_eq!(visible_bank(&cartridge, true), 0x123);
+        //the two halves are separate registers
+        cartridge.write_rom(0x2000, 0xFF);
+        assert_eq!(visible_bank(&cartridge, true), 0x1FF);
+        cartridge.write_rom(0x3000, 0x00);
+        assert_eq!(visible_bank(&cartridge, true), 0xFF);
+    }
+
+    #[test]
+    fn bank_0_is_reachable() {
+        let mut cartridge = test_cartridge(MBC5, 8, 0x00);
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(visible_bank(&cartridge, true), 0);
+        assert_eq!(visible_bank(&cartridge, false), 0);
+    }
+
+    #[test]
+    fn ram_enable_needs_exactly_0a() {
+        let mut cartridge = test_cartridge(MBC5, 4, 0x03);
+        cartridge.write_rom(0x0000, 0x1A);
+        cartridge.write_ram(0xA000, 0x55);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x03);
+        cartridge.write_ram(0xA000, 0x55);
+        assert_eq!(cartridge.read_ram(0xA000), 0x55);
+        assert_eq!(cartridge.ram[3 * 0x2000], 0x55);
+    }
+
+    #[test]
+    fn rumble_bit() {
+        let mut cartridge = test_cartridge(MBC5_RUMBLE, 4, 0x03);
+        cartridge.write_rom(0x0000, 0x0A);
+        //bit 3 drives the motor rather than selecting a RAM bank
+        cartridge.write_rom(0x4000, 0b1001);
+        assert_eq!(cartridge.take_rumble_event(), Some(true));
+        assert_eq!(cartridge.take_rumble_event(), None);
+        cartridge.write_ram(0xA000, 0x55);
+        assert_eq!(cartridge.ram[0x2000], 0x55);
+        //only changes get reported
+        cartridge.write_rom(0x4000, 0b1000);
+        assert_eq!(cartridge.take_rumble_event(), None);
+        cartridge.write_rom(0x4000, 0b0000);
+        assert_eq!(cartridge.take_rumble_event(), Some(false));
+    }
+
+    #[test]
+    fn no_rumble_without_motor() {
+        let mut cartridge = test_cartridge(MBC5, 4, 0x04);
+        cartridge.write_rom(0x4000, 0b1000);
+        assert_eq!(cartridge.take_rumble_event(), None);
+    }
+}
@@ -0,0 +1,59 @@
+use clap::Parser;
+use log::LevelFilter;
+use std::path::PathBuf;
+
+use crate::frame_pacer::{self, EmulationSpeed};
+use crate::gameboy::GbModel;
+use crate::palette::ColorScheme;
+
+/// A Game Boy emulator
+#[derive(Parser, Debug)]
+#[command(name = "gameboy", version)]
+pub(crate) struct Cli {
+    /// ROM file to run
+    pub(crate) rom: PathBuf,
+
+    /// Boot ROM to run before the cartridge.  Without one the emulator starts in the state the
+    /// boot ROM would have left it in
+    #[arg(long, value_name = "PATH")]
+    pub(crate) boot_rom: Option<PathBuf>,
+
+    /// Hardware model to emulate
+    #[arg(long, value_enum, default_value_t = GbModel::Dmg)]
+    pub(crate) model: GbModel,
+
+    /// Integer scale factor for the window
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..=16))]
+    pub(crate) scale: u32,
+
+    /// Colors to draw the four dmg shades with.  C cycles through them while running
+    #[arg(long, value_enum, default_value_t = ColorScheme::Green)]
+    pub(crate) palette: ColorScheme,
+
+    /// File of `color0 = #RRGGBB` to `color3 = #RRGGBB` lines (lightest to darkest) for the
+    /// custom palette.  Implies --palette custom unless another palette is given
+    #[arg(long, value_name = "PATH")]
+    pub(crate) palette_file: Option<PathBuf>,
+
+    /// Emulation speed, as a multiplier from 0.25 to 8 or "max" to run as fast as possible.
+    /// - and = change it while running, holding Tab fast-forwards
+    #[arg(long, default_value = "1", value_parser = frame_pacer::parse_speed)]
+    pub(crate) speed: EmulationSpeed,
+
+    /// Pace with the host clock even when the display could do it with vsync
+    #[arg(long)]
+    pub(crate) no_vsync: bool,
+
+    /// Run without opening a window
+    #[arg(long)]
+    pub(crate) headless: bool,
+
+    /// Exit after running this many frames, saving battery RAM on the way out.  Mostly for
+    /// --headless, which has no other way to exit cleanly
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) frames: Option<u64>,
+
+    /// Log level (off, error, warn, info, debug, trace).  RUST_LOG overrides this if set
+    #[arg(long, value_name = "LEVEL", default_value_t = LevelFilter::Info)]
+    pub(crate) log_level: LevelFilter,
+}
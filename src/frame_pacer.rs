@@ -0,0 +1,227 @@
+use log::debug;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// if we fall further behind than this (a slow host, a breakpoint, the window being dragged),
+// give up on catching up rather than running flat out until we have
+const MAX_FRAMES_BEHIND: u32 = 4;
+
+pub(crate) const MIN_SPEED: f64 = 0.25;
+pub(crate) const MAX_SPEED: f64 = 8.0;
+// what - and = step through
+const SPEED_STEPS: [EmulationSpeed; 7] = [
+    EmulationSpeed::Multiplier(0.25),
+    EmulationSpeed::Multiplier(0.5),
+    EmulationSpeed::Multiplier(1.0),
+    EmulationSpeed::Multiplier(2.0),
+    EmulationSpeed::Multiplier(4.0),
+    EmulationSpeed::Multiplier(8.0),
+    EmulationSpeed::Unthrottled,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EmulationSpeed {
+    // 1.0 is real time
+    Multiplier(f64),
+    // as fast as the host can go
+    Unthrottled,
+}
+
+impl EmulationSpeed {
+    pub(crate) fn faster(self) -> Self {
+        SPEED_STEPS
+            .into_iter()
+            .find(|step| step.rank() > self.rank())
+            .unwrap_or(EmulationSpeed::Unthrottled)
+    }
+    pub(crate) fn slower(self) -> Self {
+        SPEED_STEPS
+            .into_iter()
+            .rev()
+            .find(|step| step.rank() < self.rank())
+            .unwrap_or(SPEED_STEPS[0])
+    }
+    fn rank(self) -> f64 {
+        match self {
+            Self::Multiplier(multiplier) => multiplier,
+            Self::Unthrottled => f64::INFINITY,
+        }
+    }
+}
+
+impl fmt::Display for EmulationSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Multiplier(multiplier) => write!(f, "{}X", multiplier),
+            Self::Unthrottled => write!(f, "MAX"),
+        }
+    }
+}
+
+/// For clap: a multiplier between 0.25 and 8, or "max"/"unthrottled"
+pub(crate) fn parse_speed(value: &str) -> Result<EmulationSpeed, String> {
+    let value = value.trim();
+    //before stripping the x, or "max" would lose it
+    if value.eq_ignore_ascii_case("max") || value.eq_ignore_ascii_case("unthrottled") {
+        return Ok(EmulationSpeed::Unthrottled);
+    }
+    let value = value.trim_end_matches(['x', 'X']);
+    let multiplier = value
+        .parse::<f64>()
+        .map_err(|_| format!("\"{}\" isn't a speed multiplier or \"max\"", value))?;
+    if !(MIN_SPEED..=MAX_SPEED).contains(&multiplier) {
+        return Err(format!(
+            "Speed must be between {} and {}",
+            MIN_SPEED, MAX_SPEED
+        ));
+    }
+    Ok(EmulationSpeed::Multiplier(multiplier))
+}
+
+/// Keeps emulated frames in step with the host.  The emulator runs a whole frame as fast as it
+/// can and then waits until that frame is due.  At 1x on a display that runs at about the
+/// gameboy's rate, presenting with vsync already did the waiting.  Otherwise, or for frames that
+/// never got presented (e.g. with the LCD off), it sleeps against the host clock
+pub(crate) struct FramePacer {
+    // one frame at 1x
+    base_frame_duration: Duration,
+    speed: EmulationSpeed,
+    frame_duration: Duration,
+    next_frame: Instant,
+    last_present: Instant,
+    // the renderer can wait for vblank when presenting
+    vsync_available: bool,
+    // a frame has gone out through vsync since the last wait
+    vsync_presented: bool,
+}
+
+impl FramePacer {
+    pub(crate) fn new(base_frame_duration: Duration, speed: EmulationSpeed) -> Self {
+        let mut frame_pacer = Self {
+            base_frame_duration,
+            speed,
+            frame_duration: base_frame_duration,
+            next_frame: Instant::now(),
+            last_present: Instant::now(),
+            vsync_available: false,
+            vsync_presented: false,
+        };
+        frame_pacer.set_speed(speed);
+        frame_pacer
+    }
+    pub(crate) fn speed(&self) -> EmulationSpeed {
+        self.speed
+    }
+    pub(crate) fn set_vsync_available(&mut self, vsync_available: bool) {
+        self.vsync_available = vsync_available;
+        self.reset();
+    }
+    /// Whether presenting should wait for vblank.  Only at 1x, anything else would be held to
+    /// the display's rate
+    pub(crate) fn uses_vsync(&self) -> bool {
+        self.vsync_available && self.speed == EmulationSpeed::Multiplier(1.0)
+    }
+    pub(crate) fn set_speed(&mut self, speed: EmulationSpeed) {
+        debug!("Emulation speed {}", speed);
+        self.speed = speed;
+        if let EmulationSpeed::Multiplier(multiplier) = speed {
+            self.frame_duration = self.base_frame_duration.div_f64(multiplier);
+        }
+        self.reset();
+    }
+    /// Starts counting from now rather than trying to make up the old schedule, e.g. after
+    /// being paused
+    pub(crate) fn reset(&mut self) {
+        self.next_frame = Instant::now() + self.frame_duration;
+        self.vsync_presented = false;
+    }
+    pub(crate) fn wait_for_next_frame(&mut self) {
+        let now = Instant::now();
+        if std::mem::take(&mut self.vsync_presented) && self.uses_vsync() {
+            //present already waited for the display, just keep the clock in line in case the
+            //next frame doesn't get presented
+            self.next_frame = now + self.frame_duration;
+            return;
+        }
+        if self.speed == EmulationSpeed::Unthrottled {
+            self.next_frame = now;
+            return;
+        }
+        if now < self.next_frame {
+            std::thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > self.frame_duration * MAX_FRAMES_BEHIND {
+            debug!(
+                "Fell {:?} behind, resetting frame pacing",
+                now - self.next_frame
+            );
+            self.next_frame = now;
+        }
+        //deadlines are absolute so oversleeping on one frame is made up on the next
+        self.next_frame += self.frame_duration;
+    }
+    /// Above 1x there's no point drawing frames faster than the gameboy would, so this skips
+    /// the extras
+    pub(crate) fn should_present(&mut self) -> bool {
+        if self.speed.rank() <= 1.0 {
+            self.vsync_presented = self.uses_vsync();
+            return true;
+        }
+        let now = Instant::now();
+        if now - self.last_present < self.base_frame_duration {
+            return false;
+        }
+        self.last_present = now;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_speeds() {
+        assert_eq!(parse_speed("2"), Ok(EmulationSpeed::Multiplier(2.0)));
+        assert_eq!(parse_speed("0.5x"), Ok(EmulationSpeed::Multiplier(0.5)));
+        assert_eq!(parse_speed(" 4X "), Ok(EmulationSpeed::Multiplier(4.0)));
+        assert_eq!(parse_speed("MAX"), Ok(EmulationSpeed::Unthrottled));
+        assert_eq!(parse_speed("unthrottled"), Ok(EmulationSpeed::Unthrottled));
+        assert!(parse_speed("0.1").is_err());
+        assert!(parse_speed("16").is_err());
+        assert!(parse_speed("fast").is_err());
+        assert!(parse_speed("NaN").is_err());
+    }
+
+    #[test]
+    fn faster_and_slower_step() {
+        let mut speed = EmulationSpeed::Multiplier(1.0);
+        for expected in ["2X", "4X", "8X", "MAX", "MAX"] {
+            speed = speed.faster();
+            assert_eq!(speed.to_string(), expected);
+        }
+        for expected in ["8X", "4X", "2X", "1X", "0.5X", "0.25X", "0.25X"] {
+            speed = speed.slower();
+            assert_eq!(speed.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn off_step_speeds_snap_to_the_next_step() {
+        let speed = EmulationSpeed::Multiplier(1.5);
+        assert_eq!(speed.faster(), EmulationSpeed::Multiplier(2.0));
+        assert_eq!(speed.slower(), EmulationSpeed::Multiplier(1.0));
+    }
+
+    #[test]
+    fn vsync_only_at_1x() {
+        let mut frame_pacer =
+            FramePacer::new(Duration::from_millis(16), EmulationSpeed::Multiplier(1.0));
+        assert!(!frame_pacer.uses_vsync());
+        frame_pacer.set_vsync_available(true);
+        assert!(frame_pacer.uses_vsync());
+        frame_pacer.set_speed(EmulationSpeed::Multiplier(2.0));
+        assert!(!frame_pacer.uses_vsync());
+        frame_pacer.set_speed(EmulationSpeed::Unthrottled);
+        assert!(!frame_pacer.uses_vsync());
+    }
+}
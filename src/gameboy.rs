@@ -1,15 +1,127 @@
-use crate::{gb_memory, gb_registers, renderer};
-use log::info;
+use crate::{gb_memory, gb_registers, renderer, timer};
+use log::debug;
 
 pub(crate) struct Gb {
     pub(crate) registers: gb_registers::GbRegisters,
     pub(crate) gb_memory: gb_memory::GbMemory,
     pub(crate) interrupt_master_flag: bool,
+    // set by EI, IME gets turned on after the following instruction
+    pub(crate) interrupt_enable_pending: bool,
+    // cpu is asleep until IE & IF is non-zero
+    pub(crate) halted: bool,
+    // next opcode fetch doesn't advance the program counter (DMG halt bug)
+    pub(crate) halt_bug: bool,
+    // cpu, timers and ppu are asleep until a joypad line goes low
+    pub(crate) stopped: bool,
+    pub(crate) model: GbModel,
+    // cgb hardware running a cgb aware cart
+    pub(crate) cgb_mode: bool,
+    pub(crate) double_speed: bool,
+    // dots run since the start of the current frame, for pacing against the host
+    pub(crate) frame_dots: u32,
     pub(crate) renderer: renderer::GameboyRenderer,
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum GbModel {
+    // original DMG with the earliest boot rom
+    Dmg0,
+    Dmg,
+    // gameboy pocket
+    Mgb,
+    Cgb,
+}
+
+// I/O registers as the boot rom leaves them, for every model.  Mostly sound registers.  The
+// timer starts out zeroed apart from DIV, which is set separately
+const POST_BOOT_IO_REGISTERS: [(u16, u8); 31] = [
+    (0xFF00, 0xCF), // P1
+    (0xFF01, 0x00), // SB
+    (0xFF0F, 0xE1), // IF
+    (0xFF10, 0x80), // NR10
+    (0xFF11, 0xBF), // NR11
+    (0xFF12, 0xF3), // NR12
+    (0xFF13, 0xFF), // NR13
+    (0xFF14, 0xBF), // NR14
+    (0xFF16, 0x3F), // NR21
+    (0xFF17, 0x00), // NR22
+    (0xFF18, 0xFF), // NR23
+    (0xFF19, 0xBF), // NR24
+    (0xFF1A, 0x7F), // NR30
+    (0xFF1B, 0xFF), // NR31
+    (0xFF1C, 0x9F), // NR32
+    (0xFF1D, 0xFF), // NR33
+    (0xFF1E, 0xBF), // NR34
+    (0xFF20, 0xFF), // NR41
+    (0xFF21, 0x00), // NR42
+    (0xFF22, 0x00), // NR43
+    (0xFF23, 0xBF), // NR44
+    (0xFF24, 0x77), // NR50
+    (0xFF25, 0xF3), // NR51
+    (0xFF26, 0xF1), // NR52
+    (0xFF40, 0x91), // LCDC
+    (0xFF42, 0x00), // SCY
+    (0xFF43, 0x00), // SCX
+    (0xFF45, 0x00), // LYC
+    (0xFF47, 0xFC), // BGP
+    (0xFF4A, 0x00), // WY
+    (0xFF4B, 0x00), // WX
+];
+
 const LCDC_LOCATION: u16 = 0xFF40;
+const KEY1_LOCATION: u16 = 0xFF4D;
 
 impl Gb {
+    /// Puts the cpu and I/O registers in the state the boot rom for our model would have left
+    /// them in, for when we skip running it
+    pub(crate) fn apply_post_boot_state(&mut self) {
+        let model = self.model;
+        //the dmg/mgb boot roms leave H and C set unless the header checksum happens to be 0
+        let header_checksum_flags = self.gb_memory.cartridge.header.header_checksum != 0;
+        let (af, bc, de, hl) = match model {
+            GbModel::Dmg0 => (0x0100, 0xFF13, 0x00C1, 0x8403),
+            GbModel::Dmg | GbModel::Mgb => {
+                let a = if model == GbModel::Mgb { 0xFF } else { 0x01 };
+                let f = if header_checksum_flags {
+                    0b10110000
+                } else {
+                    0b10000000
+                };
+                ((a << 8) | f, 0x0013, 0x00D8, 0x014D)
+            }
+            //dmg carts on cgb get different values (some depending on the title), but these are
+            //the common case
+            GbModel::Cgb if self.cgb_mode => (0x1180, 0x0000, 0xFF56, 0x000D),
+            GbModel::Cgb => (0x1180, 0x0000, 0x0008, 0x007C),
+        };
+        self.registers.set_af(af);
+        self.registers.set_bc(bc);
+        self.registers.set_de(de);
+        self.registers.set_hl(hl);
+        self.registers.stack_pointer = 0xFFFE;
+        self.registers.program_counter = 0x0100;
+
+        //written straight into memory, these shouldn't trigger anything
+        let memory_array = &mut self.gb_memory.memory_array;
+        for (location, value) in POST_BOOT_IO_REGISTERS {
+            memory_array[location as usize] = value;
+        }
+        let (div, stat, ly, sc) = match model {
+            GbModel::Dmg0 => (0x18, 0x81, 0x91, 0x7E),
+            GbModel::Dmg | GbModel::Mgb => (0xAB, 0x85, 0x00, 0x7E),
+            GbModel::Cgb => (0x00, 0x85, 0x00, 0x7F),
+        };
+        memory_array[0xFF41] = stat;
+        memory_array[0xFF44] = ly;
+        memory_array[0xFF02] = sc;
+        memory_array[0xFF46] = if model == GbModel::Cgb { 0x00 } else { 0xFF }; // DMA
+        memory_array[0xFF48] = 0xFF; // OBP0, really uninitialized
+        memory_array[0xFF49] = 0xFF; // OBP1, really uninitialized
+        memory_array[KEY1_LOCATION as usize] = if self.cgb_mode { 0x7E } else { 0xFF };
+        memory_array[0xFF50] = 0xFF;
+        memory_array[0xFFFF] = 0x00; // IE
+        self.gb_memory.timer.set_div(div);
+    }
     pub fn get_r8(&self, register_id: u8) -> u8 {
         if register_id != 6 {
             self.registers.internal_get_r8(register_id)
@@ -27,21 +139,62 @@ impl Gb {
         }
     }
 
-    pub(crate) fn tick_renderer(&mut self) {
+    /// Advances everything that isn't the cpu by the given number of T-cycles
+    pub(crate) fn tick(&mut self, t_cycles: u32) {
+        self.gb_memory.tick_oam_dma(t_cycles);
+        self.gb_memory.tick_timers(t_cycles);
+        //the ppu doesn't care about double speed mode, it runs at the same rate regardless
+        let dots = if self.double_speed {
+            t_cycles / 2
+        } else {
+            t_cycles
+        };
+        self.frame_dots += dots;
+        self.tick_renderer(dots);
+    }
+    pub(crate) fn halt(&mut self) {
+        if !self.interrupt_master_flag && self.gb_memory.interrupt_pending() {
+            //halt bug: with IME off and an interrupt already waiting the cpu doesn't halt at
+            //all, and fails to advance the program counter on the next fetch so that byte
+            //gets read twice
+            debug!("halt bug triggered");
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
+    }
+    pub(crate) fn stop(&mut self) {
+        let key1 = self.gb_memory.read_byte(KEY1_LOCATION);
+        if self.cgb_mode && (key1 & 0b1) > 0 {
+            //armed speed switch, so this stop flips speed instead of sleeping
+            self.double_speed = !self.double_speed;
+            debug!("speed switch, double speed is now {}", self.double_speed);
+            let new_key1 = if self.double_speed { 0b10000000 } else { 0 };
+            self.gb_memory.memory_array[KEY1_LOCATION as usize] = new_key1;
+            return;
+        }
+        self.stopped = true;
+        //straight to the timer, a DMA running at the time doesn't stop this one
+        self.gb_memory.timer.write(timer::DIV_LOCATION, 0);
+    }
+    pub(crate) fn tick_renderer(&mut self, dots: u32) {
         //load LCDC control register byte
-        info!("attempting render");
-        let lcdc = self.gb_memory.read_byte(LCDC_LOCATION);
+        let lcdc = self.gb_memory.read_bus(LCDC_LOCATION);
         let lcdc_flags = renderer::RendererLcdcFlags::new(lcdc);
         if !lcdc_flags.lcd_enable {
-            info!("lcd disabled");
+            self.renderer.disable_lcd(&mut self.gb_memory);
             return;
         }
-        // self.renderer
-        //     .render_bg(lcdc_flags, self.gb_memory.memory_array)
-        self.renderer
-            .tick_dot(lcdc_flags, self.gb_memory.memory_array);
+        self.renderer.enable_lcd();
+        for _ in 0..dots {
+            self.renderer.tick_dot(&lcdc_flags, &mut self.gb_memory);
+        }
     }
     pub(crate) fn read_byte_and_advance_program_counter(&mut self) -> u8 {
+        if self.halt_bug {
+            self.halt_bug = false;
+            return self.gb_memory.read_byte(self.registers.program_counter);
+        }
         self.registers.program_counter += 1;
         self.gb_memory.read_byte(self.registers.program_counter - 1)
     }
@@ -72,7 +225,7 @@ impl Gb {
     }
     pub(crate) fn pop_stack_byte(&mut self) -> u8 {
         let read_byte_location = self.registers.stack_pointer;
-        self.registers.stack_pointer += 1;
+        self.registers.stack_pointer = self.registers.stack_pointer.wrapping_add(1);
         self.gb_memory.read_byte(read_byte_location)
     }
     pub(crate) fn pop_stack_word(&mut self) -> u16 {
@@ -81,8 +234,9 @@ impl Gb {
         read_byte_high | read_byte_low
     }
     pub(crate) fn push_stack_byte(&mut self, val: u8) {
+        //sp points at the last thing pushed, so decrement first
+        self.registers.stack_pointer = self.registers.stack_pointer.wrapping_sub(1);
         let write_byte_location = self.registers.stack_pointer;
-        self.registers.stack_pointer -= 1;
         self.gb_memory.write_byte(write_byte_location, val);
     }
     pub(crate) fn push_stack_word(&mut self, val: u16) {
@@ -1,15 +1,45 @@
 use log::debug;
 
+use crate::cartridge::Cartridge;
+use crate::joypad::{Joypad, JoypadButton};
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+use crate::timer::{DIV_LOCATION, TAC_LOCATION, Timer};
+
 pub(crate) struct GbMemory {
+    // everything that isn't on the cartridge.  the cartridge areas in here are unused
     pub(crate) memory_array: [u8; 0xFFFF + 1],
+    pub(crate) cartridge: Cartridge,
+    // mapped over the start of the cartridge until something is written to 0xFF50
+    pub(crate) boot_rom: Option<Vec<u8>>,
+    pub(crate) timer: Timer,
+    // Some while an OAM DMA transfer is running
+    pub(crate) oam_dma: Option<OamDma>,
+    pub(crate) joypad: Joypad,
+}
+
+/// A transfer of 160 bytes from XX00-XX9F into OAM, one byte per M-cycle
+pub(crate) struct OamDma {
+    source: u16,
+    bytes_copied: u16,
+    // the transfer starts one M-cycle after the write to 0xFF46
+    startup_delay: bool,
+    // T-cycles towards the next byte
+    cycles: u32,
+    // whatever was last put on the bus, which is what the cpu sees if it reads outside HRAM
+    current_byte: u8,
 }
 const INTERRUPT_FLAGS_LOCATION: u16 = 0xFF0F;
 const INTERRUPT_ENABLE_LOCATION: u16 = 0xFFFF;
-const DIV_REGISTER_LOCATION: u16 = 0xFF04;
-const TIMA_LOCATION: u16 = 0xFF05;
-const TMA_LOCATION: u16 = 0xFF06;
-const TAC_LOCATION: u16 = 0xFF07;
 const JOYP_LOCATION: u16 = 0xFF00;
+const BOOT_ROM_DISABLE_LOCATION: u16 = 0xFF50;
+const LY_LOCATION: u16 = 0xFF44;
+const STAT_LOCATION: u16 = 0xFF41;
+const OAM_DMA_LOCATION: u16 = 0xFF46;
+const OAM_LOCATION: u16 = 0xFE00;
+const OAM_DMA_LENGTH: u16 = 0xA0;
+const HRAM_START: u16 = 0xFF80;
+const HRAM_END: u16 = 0xFFFE;
+const T_CYCLES_PER_DMA_BYTE: u32 = 4;
 
 pub(crate) struct InterruptFlags {
     pub v_blank: bool,
@@ -21,78 +51,327 @@ pub(crate) struct InterruptFlags {
 impl InterruptFlags {
     pub fn get_flags_from_byte(byte: u8) -> InterruptFlags {
         InterruptFlags {
-            v_blank: (0b00010000 & byte) > 0,
-            lcd: (0b1000 & byte) > 0,
+            v_blank: (0b0001 & byte) > 0,
+            lcd: (0b0010 & byte) > 0,
             timer: (0b0100 & byte) > 0,
-            serial: (0b0010 & byte) > 0,
-            joypad: (0b0001 & byte) > 0,
+            serial: (0b1000 & byte) > 0,
+            joypad: (0b00010000 & byte) > 0,
         }
     }
     pub fn get_byte_from_flag(&self) -> u8 {
-        let v_blank = if self.v_blank { 0b10000 } else { 0 };
-        let lcd = if self.lcd { 0b1000 } else { 0 };
+        let v_blank = if self.v_blank { 0b1 } else { 0 };
+        let lcd = if self.lcd { 0b10 } else { 0 };
         let timer = if self.timer { 0b100 } else { 0 };
-        let serial = if self.serial { 0b10 } else { 0 };
-        let joypad = if self.joypad { 0b1 } else { 0 };
+        let serial = if self.serial { 0b1000 } else { 0 };
+        let joypad = if self.joypad { 0b10000 } else { 0 };
         v_blank | lcd | timer | serial | joypad
     }
 }
 
 impl GbMemory {
     pub(crate) fn read_byte(&self, address: u16) -> u8 {
+        if let Some(oam_dma) = &self.oam_dma
+            && !oam_dma.startup_delay
+            && !(HRAM_START..=HRAM_END).contains(&address)
+        {
+            //the dma owns the bus, all the cpu gets from it is whatever byte is being copied.
+            //OAM is busy being written, and IO and IE are cut off too, so they read as 0xFF
+            return match address {
+                0x0000..=0xFDFF => oam_dma.current_byte,
+                _ => 0xFF,
+            };
+        }
+        self.read_bus(address)
+    }
+    /// A read as seen by anything that isn't the cpu, so not subject to DMA
+    pub(crate) fn read_bus(&self, address: u16) -> u8 {
+        if let Some(boot_rom_byte) = self.read_boot_rom(address) {
+            return boot_rom_byte;
+        }
+        match address {
+            0x0000..=0x7FFF => return self.cartridge.read_rom(address),
+            0xA000..=0xBFFF => return self.cartridge.read_ram(address),
+            _ => (),
+        }
         if address == JOYP_LOCATION {
-            return 0xFF;
+            return self.joypad.read();
+        }
+        if (DIV_LOCATION..=TAC_LOCATION).contains(&address) {
+            return self.timer.read(address);
+        }
+        if address == INTERRUPT_FLAGS_LOCATION {
+            //only the low 5 bits exist, the rest read back as 1
+            return self.memory_array[address as usize] | 0b11100000;
         }
         self.memory_array[address as usize]
     }
     pub(crate) fn write_byte(&mut self, address: u16, value: u8) {
         debug!("Writing 0x{:02x} to 0x{:04x}", value, address);
+        if let Some(oam_dma) = &self.oam_dma
+            && !oam_dma.startup_delay
+            && !(HRAM_START..=HRAM_END).contains(&address)
+        {
+            debug!("Write to 0x{:04x} ignored during OAM DMA", address);
+            return;
+        }
+        match address {
+            0x0000..=0x7FFF => return self.cartridge.write_rom(address, value),
+            0xA000..=0xBFFF => return self.cartridge.write_ram(address, value),
+            _ => (),
+        }
+        if address == BOOT_ROM_DISABLE_LOCATION && value != 0 && self.boot_rom.is_some() {
+            //can't be mapped back in short of a reset
+            debug!("Unmapping boot ROM");
+            self.boot_rom = None;
+        }
 
-        if address == DIV_REGISTER_LOCATION {
-            self.memory_array[DIV_REGISTER_LOCATION as usize] = 0;
+        if (DIV_LOCATION..=TAC_LOCATION).contains(&address) {
+            self.timer.write(address, value);
+        } else if address == JOYP_LOCATION {
+            if self.joypad.write(value) {
+                self.request_joypad_interrupt();
+            }
+        } else if address == OAM_DMA_LOCATION {
+            //writing again mid transfer just starts over from the new source
+            self.memory_array[OAM_DMA_LOCATION as usize] = value;
+            self.oam_dma = Some(OamDma {
+                source: (value as u16) << 8,
+                bytes_copied: 0,
+                startup_delay: true,
+                cycles: 0,
+                current_byte: 0xFF,
+            });
+            debug!("OAM DMA from 0x{:02x}00", value);
+        } else if address == LY_LOCATION {
+            //read only, the ppu owns it
+        } else if address == STAT_LOCATION {
+            //only the interrupt source enables are writable
+            let stat = self.memory_array[STAT_LOCATION as usize];
+            self.memory_array[STAT_LOCATION as usize] =
+                0b10000000 | (value & 0b01111000) | (stat & 0b111);
         } else {
             self.memory_array[address as usize] = value;
         }
     }
-    pub fn tick_div(&mut self) {
-        let current_val = self.memory_array[DIV_REGISTER_LOCATION as usize];
-        self.memory_array[DIV_REGISTER_LOCATION as usize] = current_val.wrapping_add(1);
-    }
-    pub fn get_tima_ticks_per_second(&mut self) -> u64 {
-        let tac = self.memory_array[TAC_LOCATION as usize];
-        if (tac & 0b100) == 0 {
-            return 0;
+    /// The DMG boot rom covers 0x0000-0x00FF.  The CGB one is bigger and also covers
+    /// 0x0200-0x08FF, leaving the cartridge header visible in between
+    fn read_boot_rom(&self, address: u16) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref()?;
+        match address {
+            0x0000..=0x00FF | 0x0200..=0x08FF => boot_rom.get(address as usize).copied(),
+            _ => None,
         }
-        match (tac & 0b11) {
-            0x00 => 4096,
-            0x01 => 262144,
-            0x10 => 65536,
-            0x11 => 16384,
-            _ => panic!("Impossible tac state"),
+    }
+    pub(crate) fn save_state(&mut self, writer: &mut StateWriter) {
+        writer.put_bytes(&self.memory_array);
+        writer.put_bool(self.boot_rom.is_some());
+        match &self.oam_dma {
+            Some(oam_dma) => {
+                writer.put_bool(true);
+                writer.put_u16(oam_dma.source);
+                writer.put_u16(oam_dma.bytes_copied);
+                writer.put_bool(oam_dma.startup_delay);
+                writer.put_u32(oam_dma.cycles);
+                writer.put_u8(oam_dma.current_byte);
+            }
+            None => writer.put_bool(false),
         }
+        self.timer.save_state(writer);
+        self.joypad.save_state(writer);
+        self.cartridge.save_state(writer);
     }
-    pub fn tick_tima(&mut self) {
-        let current_val = self.memory_array[TIMA_LOCATION as usize];
-        let (new_val, overflow) = current_val.overflowing_add(1);
-        if !overflow {
-            self.memory_array[TIMA_LOCATION as usize] = new_val;
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.memory_array.copy_from_slice(reader.bytes(0xFFFF + 1)?);
+        if reader.bool()? {
+            if self.boot_rom.is_none() {
+                return Err(SaveStateError::Corrupt(
+                    "state was saved while running a boot ROM, start with --boot-rom to load it"
+                        .to_owned(),
+                ));
+            }
+        } else {
+            self.boot_rom = None;
+        }
+        self.oam_dma = if reader.bool()? {
+            let source = reader.u16()?;
+            let bytes_copied = reader.u16()?;
+            if bytes_copied >= OAM_DMA_LENGTH {
+                return Err(SaveStateError::Corrupt(format!(
+                    "OAM DMA at byte {}",
+                    bytes_copied
+                )));
+            }
+            Some(OamDma {
+                source,
+                bytes_copied,
+                startup_delay: reader.bool()?,
+                cycles: reader.u32()?,
+                current_byte: reader.u8()?,
+            })
         } else {
-            self.memory_array[TIMA_LOCATION as usize] = self.memory_array[TMA_LOCATION as usize];
+            None
+        };
+        self.timer.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.cartridge.load_state(reader)?;
+        Ok(())
+    }
+    pub(crate) fn tick_oam_dma(&mut self, t_cycles: u32) {
+        let Some(mut oam_dma) = self.oam_dma.take() else {
+            return;
+        };
+        oam_dma.cycles += t_cycles;
+        while oam_dma.cycles >= T_CYCLES_PER_DMA_BYTE {
+            oam_dma.cycles -= T_CYCLES_PER_DMA_BYTE;
+            if oam_dma.startup_delay {
+                oam_dma.startup_delay = false;
+                continue;
+            }
+            let mut source = oam_dma.source + oam_dma.bytes_copied;
+            //0xE000 and up is echo ram (and the dma can't see OAM or IO anyway)
+            if source >= 0xE000 {
+                source -= 0x2000;
+            }
+            let byte = self.read_bus(source);
+            self.memory_array[(OAM_LOCATION + oam_dma.bytes_copied) as usize] = byte;
+            oam_dma.current_byte = byte;
+            oam_dma.bytes_copied += 1;
+            if oam_dma.bytes_copied == OAM_DMA_LENGTH {
+                debug!("OAM DMA done");
+                return;
+            }
+        }
+        self.oam_dma = Some(oam_dma);
+    }
+    pub(crate) fn tick_timers(&mut self, t_cycles: u32) {
+        if self.timer.tick(t_cycles) {
             let mut i_f = self.read_interrupt_flags();
             i_f.timer = true;
             self.set_interrupt_flags(i_f);
         }
     }
+    // the interrupt and joypad helpers below are the hardware's own accesses, so they go around
+    // the cpu's DMA restrictions
+    pub(crate) fn interrupt_pending(&self) -> bool {
+        let i_e = self.read_bus(INTERRUPT_ENABLE_LOCATION);
+        let i_f = self.read_bus(INTERRUPT_FLAGS_LOCATION);
+        (i_e & i_f & 0b11111) != 0
+    }
+    pub(crate) fn set_button_pressed(&mut self, button: JoypadButton, pressed: bool) {
+        if self.joypad.set_pressed(button, pressed) {
+            self.request_joypad_interrupt();
+        }
+    }
+    fn request_joypad_interrupt(&mut self) {
+        let mut i_f = self.read_interrupt_flags();
+        i_f.joypad = true;
+        self.set_interrupt_flags(i_f);
+    }
+    pub(crate) fn joypad_line_low(&self) -> bool {
+        (self.read_bus(JOYP_LOCATION) & 0b1111) != 0b1111
+    }
     pub(crate) fn read_interrupt_enable(&self) -> InterruptFlags {
-        let byte = self.read_byte(INTERRUPT_ENABLE_LOCATION);
+        let byte = self.read_bus(INTERRUPT_ENABLE_LOCATION);
         InterruptFlags::get_flags_from_byte(byte)
     }
     pub(crate) fn read_interrupt_flags(&self) -> InterruptFlags {
-        let byte = self.read_byte(INTERRUPT_FLAGS_LOCATION);
+        let byte = self.read_bus(INTERRUPT_FLAGS_LOCATION);
         InterruptFlags::get_flags_from_byte(byte)
     }
     pub(crate) fn set_interrupt_flags(&mut self, i_f: InterruptFlags) {
-        let byte = i_f.get_byte_from_flag();
-        self.write_byte(INTERRUPT_FLAGS_LOCATION, byte);
+        self.memory_array[INTERRUPT_FLAGS_LOCATION as usize] = i_f.get_byte_from_flag();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_state::tests::test_machine;
+
+    /// Memory with a DMA from 0xC000 just past its startup delay, so the first byte is copied
+    fn memory_during_dma() -> GbMemory {
+        let mut gb_memory = test_machine(None).gb_memory;
+        for offset in 0..OAM_DMA_LENGTH {
+            gb_memory.write_byte(0xC000 + offset, offset as u8 ^ 0x5A);
+        }
+        gb_memory.write_byte(HRAM_START, 0x42);
+        gb_memory.write_byte(OAM_DMA_LOCATION, 0xC0);
+        gb_memory.tick_oam_dma(8);
+        gb_memory
+    }
+
+    #[test]
+    fn dma_copies_160_bytes() {
+        let mut gb_memory = memory_during_dma();
+        gb_memory.tick_oam_dma(4 * (OAM_DMA_LENGTH as u32 - 2));
+        assert!(gb_memory.oam_dma.is_some());
+        gb_memory.tick_oam_dma(4);
+        assert!(gb_memory.oam_dma.is_none());
+        for offset in 0..OAM_DMA_LENGTH {
+            assert_eq!(
+                gb_memory.read_byte(OAM_LOCATION + offset),
+                offset as u8 ^ 0x5A
+            );
+        }
+        //only 160 bytes, OAM ends there
+        assert_eq!(
+            gb_memory.memory_array[(OAM_LOCATION + OAM_DMA_LENGTH) as usize],
+            0
+        );
+    }
+
+    #[test]
+    fn reads_outside_hram_during_dma() {
+        let gb_memory = memory_during_dma();
+        assert_eq!(gb_memory.read_byte(HRAM_START), 0x42);
+        for address in [
+            OAM_LOCATION,
+            JOYP_LOCATION,
+            DIV_LOCATION,
+            INTERRUPT_ENABLE_LOCATION,
+        ] {
+            assert_eq!(gb_memory.read_byte(address), 0xFF);
+        }
+        //anything else on the bus collides with the byte the dma just copied
+        assert_eq!(gb_memory.read_byte(0xC050), 0x5A);
+        assert_eq!(gb_memory.read_byte(0x0000), 0x5A);
+    }
+
+    #[test]
+    fn writes_outside_hram_dropped_during_dma() {
+        let mut gb_memory = memory_during_dma();
+        gb_memory.write_byte(0xC100, 0x12);
+        gb_memory.write_byte(0xFF05, 0x34);
+        gb_memory.write_byte(INTERRUPT_ENABLE_LOCATION, 0x1F);
+        gb_memory.write_byte(HRAM_END, 0x56);
+        gb_memory.tick_oam_dma(4 * OAM_DMA_LENGTH as u32);
+        assert_eq!(gb_memory.read_byte(0xC100), 0x00);
+        assert_eq!(gb_memory.read_byte(0xFF05), 0x00);
+        assert_eq!(gb_memory.read_byte(INTERRUPT_ENABLE_LOCATION), 0x00);
+        assert_eq!(gb_memory.read_byte(HRAM_END), 0x56);
+    }
+
+    #[test]
+    fn startup_delay_leaves_the_bus_alone() {
+        let mut gb_memory = test_machine(None).gb_memory;
+        gb_memory.write_byte(OAM_DMA_LOCATION, 0xC0);
+        gb_memory.write_byte(0xC100, 0x34);
+        assert_eq!(gb_memory.read_byte(0xC100), 0x34);
+        //once it's going the bus is taken
+        gb_memory.tick_oam_dma(4);
+        gb_memory.write_byte(0xC100, 0x56);
+        gb_memory.tick_oam_dma(4 * OAM_DMA_LENGTH as u32);
+        assert_eq!(gb_memory.read_byte(0xC100), 0x34);
+    }
+
+    #[test]
+    fn interrupts_still_requested_during_dma() {
+        let mut gb_memory = memory_during_dma();
+        gb_memory.memory_array[INTERRUPT_ENABLE_LOCATION as usize] = 0b00100;
+        gb_memory.timer.write(TAC_LOCATION, 0b101);
+        //256 increments to overflow, then an M-cycle for the reload
+        gb_memory.tick_timers(16 * 0x100 + 4);
+        assert!(gb_memory.interrupt_pending());
+        assert!(gb_memory.read_interrupt_flags().timer);
     }
 }
@@ -1,5 +1,8 @@
+use std::fmt;
+
 use super::gb_registers_flags::GbFlagsRegister;
 
+#[derive(Clone)]
 pub(crate) struct GbRegisters {
     pub(crate) a: u8,
     pub(crate) b: u8,
@@ -27,19 +30,19 @@ impl GbRegisters {
         ((self.h as u16) << 8) | self.l as u16
     }
     pub(crate) fn set_af(&mut self, new_val: u16) {
-        self.a = ((new_val & 0xFF00) > 8) as u8;
+        self.a = ((new_val & 0xFF00) >> 8) as u8;
         self.f.set_as_f_register((new_val & 0x00FF) as u8);
     }
     pub(crate) fn set_bc(&mut self, new_val: u16) {
-        self.b = ((new_val & 0xFF00) > 8) as u8;
+        self.b = ((new_val & 0xFF00) >> 8) as u8;
         self.c = (new_val & 0x00FF) as u8;
     }
     pub(crate) fn set_de(&mut self, new_val: u16) {
-        self.d = ((new_val & 0xFF00) > 8) as u8;
+        self.d = ((new_val & 0xFF00) >> 8) as u8;
         self.e = (new_val & 0x00FF) as u8;
     }
     pub(crate) fn set_hl(&mut self, new_val: u16) {
-        self.h = ((new_val & 0xFF00) > 8) as u8;
+        self.h = ((new_val & 0xFF00) >> 8) as u8;
         self.l = (new_val & 0x00FF) as u8;
     }
     pub(crate) fn get_r16(&self, register_id: u8) -> u16 {
@@ -125,3 +128,23 @@ impl GbRegisters {
         }
     }
 }
+
+impl fmt::Display for GbRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        write!(
+            f,
+            "AF=0x{:04x} BC=0x{:04x} DE=0x{:04x} HL=0x{:04x} SP=0x{:04x} PC=0x{:04x} [{}{}{}{}]",
+            self.get_af(),
+            self.get_bc(),
+            self.get_de(),
+            self.get_hl(),
+            self.stack_pointer,
+            self.program_counter,
+            flag(self.f.z, 'Z'),
+            flag(self.f.n, 'N'),
+            flag(self.f.h, 'H'),
+            flag(self.f.c, 'C'),
+        )
+    }
+}
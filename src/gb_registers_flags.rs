@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub(crate) struct GbFlagsRegister {
     pub(crate) z: bool, // Zero flag
     pub(crate) n: bool, // Subtraction flag (BCD)
@@ -0,0 +1,164 @@
+use log::debug;
+
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum JoypadButton {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl JoypadButton {
+    /// Bit in the JOYP low nibble when the button's group is selected
+    fn bit(&self) -> u8 {
+        match self {
+            Self::Right | Self::A => 0b0001,
+            Self::Left | Self::B => 0b0010,
+            Self::Up | Self::Select => 0b0100,
+            Self::Down | Self::Start => 0b1000,
+        }
+    }
+    fn is_d_pad(&self) -> bool {
+        matches!(self, Self::Right | Self::Left | Self::Up | Self::Down)
+    }
+}
+
+/// The buttons are wired up as a 2x4 matrix.  The game pulls P14 (bit 4) low to read the d-pad
+/// and/or P15 (bit 5) low to read the buttons, and pressed buttons pull their line low
+pub(crate) struct Joypad {
+    // pressed buttons as 1s, in JOYP bit order
+    d_pad: u8,
+    buttons: u8,
+    // bits 4 and 5 as last written
+    select: u8,
+}
+
+impl Joypad {
+    pub(crate) fn new() -> Self {
+        Self {
+            d_pad: 0,
+            buttons: 0,
+            select: 0b00110000,
+        }
+    }
+    pub(crate) fn read(&self) -> u8 {
+        //unused bits 6 and 7 read as 1
+        0b11000000 | self.select | (!self.pressed_lines() & 0b1111)
+    }
+    /// Only the select bits are writable.  Returns true if that pulled a line low
+    pub(crate) fn write(&mut self, value: u8) -> bool {
+        let lines_before = self.pressed_lines();
+        self.select = value & 0b00110000;
+        self.lines_fell(lines_before)
+    }
+    /// Returns true if the press pulled a line low, which is what raises the joypad interrupt
+    pub(crate) fn set_pressed(&mut self, button: JoypadButton, pressed: bool) -> bool {
+        debug!(
+            "{:?} {}",
+            button,
+            if pressed { "pressed" } else { "released" }
+        );
+        let lines_before = self.pressed_lines();
+        let group = if button.is_d_pad() {
+            &mut self.d_pad
+        } else {
+            &mut self.buttons
+        };
+        if pressed {
+            *group |= button.bit();
+        } else {
+            *group &= !button.bit();
+        }
+        self.lines_fell(lines_before)
+    }
+    /// Which buttons are down comes from the host, so only the game's side gets saved
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u8(self.select);
+    }
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.select = reader.u8()? & 0b00110000;
+        Ok(())
+    }
+    /// The low nibble lines currently pulled low, as 1s
+    fn pressed_lines(&self) -> u8 {
+        let d_pad = if (self.select & 0b00010000) == 0 {
+            self.d_pad
+        } else {
+            0
+        };
+        let buttons = if (self.select & 0b00100000) == 0 {
+            self.buttons
+        } else {
+            0
+        };
+        d_pad | buttons
+    }
+    fn lines_fell(&self, lines_before: u8) -> bool {
+        (self.pressed_lines() & !lines_before) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SELECT_D_PAD: u8 = 0b00100000;
+    const SELECT_BUTTONS: u8 = 0b00010000;
+    const SELECT_NONE: u8 = 0b00110000;
+
+    #[test]
+    fn nothing_selected_reads_high() {
+        let mut joypad = Joypad::new();
+        joypad.set_pressed(JoypadButton::A, true);
+        joypad.set_pressed(JoypadButton::Down, true);
+        assert_eq!(joypad.read(), 0xFF);
+    }
+
+    #[test]
+    fn groups_read_through_their_select_line() {
+        let mut joypad = Joypad::new();
+        joypad.set_pressed(JoypadButton::Left, true);
+        joypad.set_pressed(JoypadButton::Start, true);
+        joypad.write(SELECT_D_PAD);
+        assert_eq!(joypad.read(), 0b11101101);
+        joypad.write(SELECT_BUTTONS);
+        assert_eq!(joypad.read(), 0b11010111);
+        //with both selected the lines are shared, so presses from both groups show up
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0b11000101);
+        joypad.set_pressed(JoypadButton::Left, false);
+        assert_eq!(joypad.read(), 0b11000111);
+    }
+
+    #[test]
+    fn only_the_select_bits_are_writable() {
+        let mut joypad = Joypad::new();
+        joypad.write(0b11011111);
+        assert_eq!(joypad.read(), 0xDF);
+    }
+
+    #[test]
+    fn interrupt_on_falling_lines() {
+        let mut joypad = Joypad::new();
+        //a press in an unselected group doesn't pull anything low
+        assert!(!joypad.set_pressed(JoypadButton::B, true));
+        joypad.write(SELECT_NONE);
+        //but selecting the group with it held down does
+        assert!(joypad.write(SELECT_BUTTONS));
+        assert!(!joypad.write(SELECT_BUTTONS));
+        //a line that's already low can't fall again
+        assert!(!joypad.set_pressed(JoypadButton::B, true));
+        assert!(joypad.set_pressed(JoypadButton::A, true));
+        assert!(!joypad.set_pressed(JoypadButton::A, false));
+        //Right shares A's line, which is only low from the button group
+        joypad.set_pressed(JoypadButton::A, true);
+        joypad.write(0x00);
+        assert!(!joypad.set_pressed(JoypadButton::Right, true));
+    }
+}
@@ -1,13 +1,26 @@
 extern crate pretty_env_logger;
+use clap::Parser;
 use gb_memory::InterruptFlags;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use sdl2::{event::Event, keyboard::Keycode, pixels::Color, render::WindowCanvas, sys::KeyCode};
-use std::{fs, ops::ControlFlow, time::Duration};
+use std::{fmt, fs, ops::ControlFlow, path::Path, time::Duration};
+mod cartridge;
+mod cartridge_header;
+mod cartridge_mbc1;
+mod cartridge_mbc2;
+mod cartridge_mbc3;
+mod cartridge_mbc5;
+mod cli;
+mod frame_pacer;
 mod gameboy;
 mod gb_memory;
 mod gb_registers;
 mod gb_registers_flags;
+mod joypad;
+mod palette;
 mod renderer;
+mod save_state;
+mod timer;
 
 //==================================================DEBUG
 const PANIC_ON_UNDEFINED_OPCODE: bool = true;
@@ -15,13 +28,13 @@ const PANIC_ON_UNDEFINED_OPCODE: bool = true;
 //==================================================TIMINGS
 const OPS_PER_SEC: u64 = 4_194_304;
 const NS_PER_SEC: u64 = 1_000_000_000;
-const NS_PER_OP: u64 = OPS_PER_SEC / NS_PER_SEC;
+// 154 lines of 456 dots
+const DOTS_PER_FRAME: u32 = 70224;
+const NS_PER_FRAME: u64 = DOTS_PER_FRAME as u64 * NS_PER_SEC / OPS_PER_SEC;
 
-const DIV_PER_SEC: u64 = 16_384;
-const NS_PER_DIV: u64 = DIV_PER_SEC / NS_PER_SEC;
-
-const PPU_DOTS_PER_SEC: u64 = 4_194_304;
-const NS_PER_PPU_DOT: u64 = PPU_DOTS_PER_SEC / NS_PER_SEC;
+//==================================================SAVES
+// flush dirty battery RAM to disk at least this often, in emulated frames (about 5 seconds)
+const SAVE_FLUSH_FRAMES: u32 = 300;
 
 //==================================================DISPLAY
 const GAMEBOY_WIDTH: usize = 160;
@@ -36,115 +49,327 @@ fn calculate_word_half_carry_add(a: u16, b: u16) -> bool {
     (a & 0xFF) + (b & 0xFF) > 0xFF
 }
 fn calculate_byte_half_carry_sub(a: u8, b: u8) -> bool {
-    unimplemented!("uhh")
-    // uhh
+    //same idea as the add, but backwards: if the low nibble of b is bigger than the
+    //low nibble of a then we had to borrow from bit 4
+    (a & 0x0F) < (b & 0x0F)
+}
+fn calculate_byte_half_carry_sbc(a: u8, b: u8, carry: bool) -> bool {
+    let carry_sub = if carry { 0b1 } else { 0b0 };
+    (a & 0x0F) < (b & 0x0F) + carry_sub
+}
+fn calculate_byte_carry_sbc(a: u8, b: u8, carry: bool) -> bool {
+    let carry_sub = if carry { 0b1 } else { 0b0 };
+    (a as u16) < (b as u16) + carry_sub
 }
 
 fn main() {
     //pre-init
-    pretty_env_logger::init();
-    let mut sdl_backend = renderer::SdlBackend::new().expect("Unable to initalize SDL2 Backend");
-    let mut event_pump = sdl_backend
-        .get_event_pump()
-        .expect("Unable to get event pump from SDL2 Backend");
-    let mut renderer = renderer::GameboyRenderer::new(&mut sdl_backend)
-        .expect("Unable to create window for gameboy renderer");
+    let cli = cli::Cli::parse();
+    let mut logger = pretty_env_logger::formatted_builder();
+    logger.filter_level(cli.log_level);
+    if let Ok(rust_log) = std::env::var("RUST_LOG") {
+        logger.parse_filters(&rust_log);
+    }
+    logger.init();
+
+    let (mut event_pump, mut renderer) = if cli.headless {
+        info!("Running headless");
+        if cli.frames.is_none() {
+            warn!("Without --frames the only way out is Ctrl-C, which skips saving battery RAM");
+        }
+        (None, renderer::GameboyRenderer::new_headless())
+    } else {
+        let mut sdl_backend =
+            renderer::SdlBackend::new().expect("Unable to initalize SDL2 Backend");
+        let event_pump = sdl_backend
+            .get_event_pump()
+            .expect("Unable to get event pump from SDL2 Backend");
+        let renderer = renderer::GameboyRenderer::new(&mut sdl_backend, cli.scale, !cli.no_vsync)
+            .expect("Unable to create window for gameboy renderer");
+        (Some(event_pump), renderer)
+    };
+
+    let custom_colors = cli.palette_file.as_deref().map(|path| {
+        palette::read_palette_file(path).unwrap_or_else(|e| {
+            error!("Unable to read palette file {}", e);
+            std::process::exit(1);
+        })
+    });
+    //a palette file on its own means use it
+    let color_scheme = if custom_colors.is_some() && cli.palette == palette::ColorScheme::Green {
+        palette::ColorScheme::Custom
+    } else {
+        cli.palette
+    };
+    renderer.display_palette = palette::DisplayPalette::new(color_scheme, custom_colors);
 
     //init
+    let rom_path = cli.rom.clone();
+    let boot_rom = cli.boot_rom.as_deref().map(read_boot_rom);
+    let mut cartridge = read_rom(&rom_path, boot_rom.is_some());
+    renderer.set_title(&format!("Gameboy - {}", cartridge.header.title));
+    cartridge
+        .attach_save_file(rom_path.with_extension("sav"))
+        .expect("Unable to read save file");
+    //cgb hardware runs dmg carts in a compatibility mode without the cgb extras
+    let cgb_mode = cli.model == gameboy::GbModel::Cgb
+        && cartridge.header.cgb_support != cartridge_header::CgbSupport::DmgOnly;
     let mut gb = gameboy::Gb {
+        //everything starts zeroed, either the boot rom or apply_post_boot_state fills it in
         registers: gb_registers::GbRegisters {
-            a: 0x01,
-            b: 0xFF,
-            c: 0x13,
+            a: 0x00,
+            b: 0x00,
+            c: 0x00,
             d: 0x00,
-            e: 0xC1,
-            h: 0x84,
-            l: 0x03,
+            e: 0x00,
+            h: 0x00,
+            l: 0x00,
             f: gb_registers_flags::GbFlagsRegister {
                 z: false,
                 n: false,
                 h: false,
                 c: false,
             },
-            stack_pointer: 0xFFFE, //stack_pointer starts at 0xfffe per docs!
+            stack_pointer: 0x0000,
             program_counter: 0u16,
         },
         gb_memory: gb_memory::GbMemory {
             memory_array: [0u8; 0x0FFFF + 1],
+            cartridge,
+            boot_rom,
+            timer: timer::Timer::new(),
+            oam_dma: None,
+            joypad: joypad::Joypad::new(),
         },
         interrupt_master_flag: false,
+        interrupt_enable_pending: false,
+        halted: false,
+        halt_bug: false,
+        stopped: false,
+        model: cli.model,
+        cgb_mode,
+        double_speed: false,
+        frame_dots: 0,
         renderer,
     };
-    read_rom(&mut gb.gb_memory);
-    gb.registers.program_counter = 0x100;
-    gb.gb_memory.memory_array[0xFF40] = 0x91;
-
-    let mut elapsed_time = 0u64;
-    let mut render_counter = 0u64;
-    let mut div_timer = 0u64;
-    let mut tima_timer = 0u64;
+    if gb.gb_memory.boot_rom.is_none() {
+        gb.apply_post_boot_state();
+    }
+
+    let mut frames_since_save_flush = 0u32;
+    let mut frames_run = 0u64;
+    let mut frame_pacer =
+        frame_pacer::FramePacer::new(Duration::from_nanos(NS_PER_FRAME), cli.speed);
+    frame_pacer.set_vsync_available(gb.renderer.vsync);
+    set_speed(&mut gb, &mut frame_pacer, cli.speed);
+    // the speed to go back to when Tab is let go
+    let mut speed_before_fast_forward = None;
+    let mut paused = false;
+    // while paused, what we've been asked to run before pausing again
+    let mut step: Option<Step> = None;
+    let mut save_state_slot = 1u8;
     //Main loop
     'mainloop: loop {
-        //interrupt checking
-        check_interrupts(&mut gb);
+        //pacing: run a frame flat out, then wait for the host to catch up
+        let frame_done = gb.frame_dots >= DOTS_PER_FRAME;
+        if frame_done {
+            gb.frame_dots -= DOTS_PER_FRAME;
+            if step == Some(Step::Frame) {
+                step = None;
+                info!("Frame advanced");
+            }
+            if !paused {
+                frame_pacer.wait_for_next_frame();
+            }
 
-        //input parsing
+            //battery saves.  Checked here rather than after each instruction so that frames
+            //spent halted count too
+            frames_since_save_flush += 1;
+            let cartridge = &mut gb.gb_memory.cartridge;
+            if cartridge.flush_requested
+                || (frames_since_save_flush >= SAVE_FLUSH_FRAMES && cartridge.ram_dirty)
+            {
+                frames_since_save_flush = 0;
+                if let Err(e) = cartridge.write_save_file() {
+                    error!("Unable to write save file: {}", e);
+                }
+            }
 
-        'input_parsing: for event in event_pump.poll_iter() {
-            match event {
-                Event::KeyDown {
-                    keycode: Some(Keycode::ESCAPE),
-                    ..
-                } => break 'mainloop,
-                _ => (),
+            frames_run += 1;
+            if cli.frames == Some(frames_run) {
+                info!("Ran {} frames, exiting", frames_run);
+                break 'mainloop;
             }
         }
 
-        //opcode parsing
-        let read_program_counter = gb.registers.program_counter;
-        let query_byte = gb.read_byte_and_advance_program_counter();
-        debug!("=== === ===");
-        debug!("0x{:04x}: 0x{:02x}", read_program_counter, query_byte);
-        if let ControlFlow::Break(_) = execute_op(&mut gb, query_byte) {
-            //
-        } else {
-            error!("Previous opcode is undefined! 0x{:02x}", query_byte);
-            if PANIC_ON_UNDEFINED_OPCODE {
-                unimplemented!(
-                    "Undefined opcode! 0x{:04x}: 0x{:02x} / 0b{:08b}",
-                    read_program_counter,
-                    query_byte,
-                    query_byte
-                );
+        //interrupt checking
+        if !paused || step.is_some() {
+            let interrupt_cycles = check_interrupts(&mut gb);
+            if interrupt_cycles > 0 {
+                gb.tick(interrupt_cycles);
             }
         }
-        ::std::thread::sleep(Duration::from_nanos(NS_PER_OP)); // 4.194304 MHz
-        elapsed_time += NS_PER_OP;
-        render_counter += NS_PER_OP;
-        div_timer += NS_PER_OP;
-        tima_timer += NS_PER_OP;
 
-        let tima_tps = gb.gb_memory.get_tima_ticks_per_second();
-        if tima_tps != 0 {
-            let ns_per_tick = NS_PER_SEC / tima_tps;
-            if tima_timer >= ns_per_tick {
-                gb.gb_memory.tick_tima();
-                tima_timer = 0;
+        //input parsing
+        //once a frame is plenty, except in stop or paused where nothing else is moving
+        if (frame_done || gb.stopped || (paused && step.is_none()))
+            && let Some(event_pump) = event_pump.as_mut()
+        {
+            for event in event_pump.poll_iter() {
+                match event {
+                    Event::Quit { .. }
+                    | Event::KeyDown {
+                        keycode: Some(Keycode::ESCAPE),
+                        ..
+                    } => break 'mainloop,
+                    Event::KeyDown {
+                        keycode: Some(Keycode::C),
+                        repeat: false,
+                        ..
+                    } => {
+                        gb.renderer.display_palette.cycle();
+                        info!("Palette: {:?}", gb.renderer.display_palette.scheme);
+                        //redraw now so the change shows up even if the lcd is off
+                        gb.renderer.render_current_display();
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::P),
+                        repeat: false,
+                        ..
+                    } => {
+                        paused = !paused;
+                        step = None;
+                        info!("{}", if paused { "Paused" } else { "Resumed" });
+                        if !paused {
+                            frame_pacer.reset();
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(keycode @ (Keycode::N | Keycode::I)),
+                        ..
+                    } if paused && step.is_none() => {
+                        step = Some(if keycode == Keycode::N {
+                            Step::Frame
+                        } else {
+                            Step::Instruction
+                        });
+                        //a frame advance runs until the next frame boundary, so line up with one
+                        if step == Some(Step::Frame) {
+                            gb.frame_dots = 0;
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F5),
+                        repeat: false,
+                        ..
+                    } => {
+                        let path = save_state::slot_path(&rom_path, save_state_slot);
+                        if let Err(e) = save_state::save_state(&mut gb, &path) {
+                            error!("Unable to save state to {}: {}", path.display(), e);
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F8),
+                        repeat: false,
+                        ..
+                    } => {
+                        let path = save_state::slot_path(&rom_path, save_state_slot);
+                        match save_state::load_state(&mut gb, &path) {
+                            Ok(header) => {
+                                debug!("State was saved at unix time {}", header.timestamp);
+                                gb.renderer.render_current_display();
+                                frame_pacer.reset();
+                            }
+                            Err(e) => {
+                                error!("Unable to load state from {}: {}", path.display(), e)
+                            }
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::TAB),
+                        repeat: false,
+                        ..
+                    } => {
+                        speed_before_fast_forward = Some(frame_pacer.speed());
+                        set_speed(
+                            &mut gb,
+                            &mut frame_pacer,
+                            frame_pacer::EmulationSpeed::Unthrottled,
+                        );
+                    }
+                    Event::KeyUp {
+                        keycode: Some(Keycode::TAB),
+                        ..
+                    } => {
+                        if let Some(speed) = speed_before_fast_forward.take() {
+                            set_speed(&mut gb, &mut frame_pacer, speed);
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(keycode @ (Keycode::MINUS | Keycode::EQUALS)),
+                        ..
+                    } => {
+                        let speed = if keycode == Keycode::MINUS {
+                            frame_pacer.speed().slower()
+                        } else {
+                            frame_pacer.speed().faster()
+                        };
+                        //changing speed mid fast-forward sticks once Tab is let go
+                        speed_before_fast_forward = None;
+                        set_speed(&mut gb, &mut frame_pacer, speed);
+                        info!("Speed: {}", speed);
+                    }
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        repeat: false,
+                        ..
+                    } => {
+                        if let Some(button) = keycode_to_button(keycode) {
+                            gb.gb_memory.set_button_pressed(button, true);
+                        } else if let Some(slot) = keycode_to_slot(keycode) {
+                            save_state_slot = slot;
+                            info!("Save state slot {}", save_state_slot);
+                        }
+                    }
+                    Event::KeyUp {
+                        keycode: Some(keycode),
+                        ..
+                    } => {
+                        if let Some(button) = keycode_to_button(keycode) {
+                            gb.gb_memory.set_button_pressed(button, false);
+                        }
+                    }
+                    _ => (),
+                }
             }
         }
 
-        // if div_timer >= NS_PER_DIV {
-        // let times_to_tick = div_timer / NS_PER_DIV;
-        let times_to_tick = 1;
-        for _ in 1..times_to_tick {
-            gb.gb_memory.tick_div();
-            div_timer = 0;
+        if paused && step.is_none() {
+            ::std::thread::sleep(Duration::from_millis(1));
+            continue 'mainloop;
         }
-        // }
-        // if render_counter >= NS_PER_PPU_DOT {
-        // gb.tick_renderer();
-        // render_counter = 0;
-        // }
+
+        //low power modes and opcode parsing
+        let cpu_step = step_cpu(&mut gb);
+        if step == Some(Step::Instruction) {
+            step = None;
+            report_step(&gb, &cpu_step.to_string());
+        }
+        match cpu_step {
+            CpuStep::Stopped => {
+                //everything is frozen in stop, including the timers and ppu, so just wait on
+                //the host for a bit
+                ::std::thread::sleep(Duration::from_millis(1));
+                continue 'mainloop;
+            }
+            CpuStep::Halted => continue 'mainloop,
+            CpuStep::Op { .. } => (),
+        }
+
+        if let Some(rumble_active) = gb.gb_memory.cartridge.take_rumble_event() {
+            info!("Rumble motor {}", if rumble_active { "on" } else { "off" });
+        }
+
         // gb.renderer.render_current_display();
         // let current_display = gb.renderer.current_display;
         //
@@ -156,81 +381,246 @@ fn main() {
         // }
 
         //rendering
+        if gb.renderer.frame_ready {
+            gb.renderer.frame_ready = false;
+            if frame_pacer.should_present() {
+                gb.renderer.render_current_display();
+            }
+        }
         // if render_counter >= NS_PER_OP * 10 {
         //     gb.render();
         //     render_counter = 0;
         // }
     }
+
+    //shutdown
+    if gb.gb_memory.cartridge.has_battery {
+        info!("Saving before exit");
+        if let Err(e) = gb.gb_memory.cartridge.write_save_file() {
+            error!("Unable to write save file: {}", e);
+        }
+    }
+}
+
+/// What the frontend runs while paused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    // until the next frame boundary
+    Frame,
+    // a single opcode
+    Instruction,
 }
 
-fn check_interrupts(gb: &mut gameboy::Gb) {
-    if gb.interrupt_master_flag {
-        let i_e = gb.gb_memory.read_interrupt_enable();
-        let i_f = gb.gb_memory.read_interrupt_flags();
-        let interupts = InterruptFlags::get_flags_from_byte(
-            i_e.get_byte_from_flag() | i_f.get_byte_from_flag(),
+/// Prints what an instruction step just did and the registers it left behind.  Straight to
+/// stdout rather than the log, so stepping shows something whatever the log level
+fn report_step(gb: &gameboy::Gb, stepped: &str) {
+    println!("{}", stepped);
+    println!("{} IME={}", gb.registers, gb.interrupt_master_flag);
+}
+
+/// Changes the emulation speed, shows it in the corner of the screen whenever it isn't 1x, and
+/// only waits for vsync when presenting at 1x
+fn set_speed(
+    gb: &mut gameboy::Gb,
+    frame_pacer: &mut frame_pacer::FramePacer,
+    speed: frame_pacer::EmulationSpeed,
+) {
+    frame_pacer.set_speed(speed);
+    let vsync = frame_pacer.uses_vsync();
+    if gb.renderer.vsync != vsync
+        && let Err(e) = gb.renderer.set_vsync(vsync)
+    {
+        warn!(
+            "Unable to turn vsync {}, pacing with the host clock instead: {}",
+            if vsync { "on" } else { "off" },
+            e
         );
-        let interrupt_call_location = match interupts {
-            InterruptFlags { v_blank: true, .. } => {
-                let mut new_if = i_f;
-                new_if.v_blank = false;
-                gb.gb_memory.set_interrupt_flags(new_if);
-                0x40u16
-            }
-            InterruptFlags { lcd: true, .. } => {
-                let mut new_if = i_f;
-                new_if.lcd = false;
-                gb.gb_memory.set_interrupt_flags(new_if);
-                0x48u16
-            }
-            InterruptFlags { timer: true, .. } => {
-                let mut new_if = i_f;
-                new_if.timer = false;
-                gb.gb_memory.set_interrupt_flags(new_if);
-                0x50u16
-            }
-            InterruptFlags { serial: true, .. } => {
-                let mut new_if = i_f;
-                new_if.serial = false;
-                gb.gb_memory.set_interrupt_flags(new_if);
-                0x58u16
-            }
-            InterruptFlags { joypad: true, .. } => {
-                let mut new_if = i_f;
-                new_if.joypad = false;
-                gb.gb_memory.set_interrupt_flags(new_if);
-                0x60u16
-            }
-            _ => 0x0u16,
-        };
-        if interrupt_call_location != 0 {
-            info!(
-                "Interrupt called! Sending you to 0x{:2x}",
-                interrupt_call_location
-            );
-            gb.interrupt_master_flag = false;
-            gb.push_stack_word(gb.registers.program_counter);
-            gb.registers.program_counter = interrupt_call_location;
+        frame_pacer.set_vsync_available(false);
+    }
+    gb.renderer.overlay_text = if speed == frame_pacer::EmulationSpeed::Multiplier(1.0) {
+        None
+    } else {
+        Some(speed.to_string())
+    };
+}
+
+/// The number keys pick a save state slot
+fn keycode_to_slot(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::NUM_1 => Some(1),
+        Keycode::NUM_2 => Some(2),
+        Keycode::NUM_3 => Some(3),
+        Keycode::NUM_4 => Some(4),
+        Keycode::NUM_5 => Some(5),
+        Keycode::NUM_6 => Some(6),
+        Keycode::NUM_7 => Some(7),
+        Keycode::NUM_8 => Some(8),
+        Keycode::NUM_9 => Some(9),
+        _ => None,
+    }
+}
+
+/// Default keyboard layout: arrows for the d-pad, Z/X for A/B, Enter for start and right shift
+/// or backspace for select
+fn keycode_to_button(keycode: Keycode) -> Option<joypad::JoypadButton> {
+    match keycode {
+        Keycode::RIGHT => Some(joypad::JoypadButton::Right),
+        Keycode::LEFT => Some(joypad::JoypadButton::Left),
+        Keycode::UP => Some(joypad::JoypadButton::Up),
+        Keycode::DOWN => Some(joypad::JoypadButton::Down),
+        Keycode::Z => Some(joypad::JoypadButton::A),
+        Keycode::X => Some(joypad::JoypadButton::B),
+        Keycode::RETURN => Some(joypad::JoypadButton::Start),
+        Keycode::RSHIFT | Keycode::BACKSPACE => Some(joypad::JoypadButton::Select),
+        _ => None,
+    }
+}
+
+/// What the cpu did on one trip round the main loop
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CpuStep {
+    // asleep in stop, nothing moved
+    Stopped,
+    // asleep in halt for an M-cycle
+    Halted,
+    // ran the opcode read from `address`
+    Op { address: u16, opcode: u8 },
+}
+
+impl fmt::Display for CpuStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stopped => write!(f, "Stopped"),
+            Self::Halted => write!(f, "Halted"),
+            Self::Op { address, opcode } => write!(f, "0x{:04x}: 0x{:02x}", address, opcode),
+        }
+    }
+}
+
+/// Wakes the cpu from stop or halt if it's time to, otherwise runs the next opcode.  Everything
+/// else is ticked along with it, apart from in stop where it's all frozen
+fn step_cpu(gb: &mut gameboy::Gb) -> CpuStep {
+    if gb.stopped {
+        if !gb.gb_memory.joypad_line_low() {
+            return CpuStep::Stopped;
+        }
+        debug!("leaving stop");
+        gb.stopped = false;
+    }
+    if gb.halted {
+        if !gb.gb_memory.interrupt_pending() {
+            gb.tick(4);
+            return CpuStep::Halted;
         }
+        //wakes up even with IME off, we just won't jump to the handler
+        debug!("leaving halt");
+        gb.halted = false;
+    }
+    let (address, opcode, t_cycles) = run_next_op(gb);
+    //keep the timers and ppu in lockstep with whatever the cpu just did
+    gb.tick(t_cycles);
+    CpuStep::Op { address, opcode }
+}
+
+/// Fetches and runs the next opcode, returning where it was read from, the opcode itself and the
+/// number of T-cycles it took
+fn run_next_op(gb: &mut gameboy::Gb) -> (u16, u8, u32) {
+    //EI only takes effect once the instruction after it has finished, and not at all if that
+    //instruction was a DI
+    let interrupt_enable_was_pending = gb.interrupt_enable_pending;
+    let read_program_counter = gb.registers.program_counter;
+    let query_byte = gb.read_byte_and_advance_program_counter();
+    debug!("=== === ===");
+    debug!("0x{:04x}: 0x{:02x}", read_program_counter, query_byte);
+    let t_cycles = match execute_op(gb, query_byte) {
+        ControlFlow::Break(t_cycles) => t_cycles,
+        ControlFlow::Continue(()) => {
+            error!("Previous opcode is undefined! 0x{:02x}", query_byte);
+            if PANIC_ON_UNDEFINED_OPCODE {
+                unimplemented!(
+                    "Undefined opcode! 0x{:04x}: 0x{:02x} / 0b{:08b}",
+                    read_program_counter,
+                    query_byte,
+                    query_byte
+                );
+            }
+            //treat it like a nop so the rest of the machine keeps moving
+            4
+        }
+    };
+    if interrupt_enable_was_pending && gb.interrupt_enable_pending {
+        gb.interrupt_enable_pending = false;
+        gb.interrupt_master_flag = true;
+    }
+    (read_program_counter, query_byte, t_cycles)
+}
+
+/// Services the highest priority interrupt that is both enabled (IE) and requested (IF),
+/// returning the number of T-cycles the dispatch took (0 if nothing was serviced)
+fn check_interrupts(gb: &mut gameboy::Gb) -> u32 {
+    if !gb.interrupt_master_flag {
+        return 0;
     }
+    let i_e = gb.gb_memory.read_interrupt_enable();
+    let mut i_f = gb.gb_memory.read_interrupt_flags();
+    let interrupts =
+        InterruptFlags::get_flags_from_byte(i_e.get_byte_from_flag() & i_f.get_byte_from_flag());
+    //match arms are checked top to bottom, which lines up with the hardware priority
+    let interrupt_call_location = match interrupts {
+        InterruptFlags { v_blank: true, .. } => {
+            i_f.v_blank = false;
+            0x40u16
+        }
+        InterruptFlags { lcd: true, .. } => {
+            i_f.lcd = false;
+            0x48u16
+        }
+        InterruptFlags { timer: true, .. } => {
+            i_f.timer = false;
+            0x50u16
+        }
+        InterruptFlags { serial: true, .. } => {
+            i_f.serial = false;
+            0x58u16
+        }
+        InterruptFlags { joypad: true, .. } => {
+            i_f.joypad = false;
+            0x60u16
+        }
+        _ => return 0,
+    };
+    debug!(
+        "Interrupt called! Sending you to 0x{:02x}",
+        interrupt_call_location
+    );
+    gb.gb_memory.set_interrupt_flags(i_f);
+    gb.interrupt_master_flag = false;
+    gb.interrupt_enable_pending = false;
+    gb.halted = false;
+    gb.push_stack_word(gb.registers.program_counter);
+    gb.registers.program_counter = interrupt_call_location;
+    //2 wait states, 2 for the push and 1 for the jump
+    20
 }
 
-fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
+/// Executes a single opcode, returning `ControlFlow::Break` with the number of T-cycles it took
+/// or `ControlFlow::Continue` if the opcode is undefined
+fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<u32> {
     match query_byte >> 6 {
         0b00 => {
             debug!("Opcode group 0");
             //NOP
             if query_byte == 0 {
                 debug!("NOP");
-                return ControlFlow::Break(());
+                return ControlFlow::Break(4);
             }
             //LD r16, imm16
             if query_byte & 0b1111 == 0b0001 {
                 debug!("LD r16, imm16");
                 let write_word = gb.read_word_and_advance_program_counter();
-                let register = (query_byte | 0b00110000) >> 4;
+                let register = (query_byte & 0b00110000) >> 4;
                 gb.registers.set_r16(register, write_word);
-                return ControlFlow::Break(());
+                return ControlFlow::Break(12);
             }
             //LD r16mem, a
             if query_byte & 0b1111 == 0b0010 {
@@ -239,7 +629,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 let write_location = gb.registers.get_r16mem(register_id);
                 let write_byte = gb.registers.a;
                 gb.gb_memory.write_byte(write_location, write_byte);
-                return ControlFlow::Break(());
+                return ControlFlow::Break(8);
             }
             //LD a, r16mem
             if query_byte & 0b1111 == 0b1010 {
@@ -248,33 +638,35 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 let read_location = gb.registers.get_r16mem(register_id);
                 let write_byte = gb.gb_memory.read_byte(read_location);
                 gb.registers.a = write_byte;
-                return ControlFlow::Break(());
+                return ControlFlow::Break(8);
             }
             //LD [imm16], sp
             if query_byte == 0b00001000 {
                 debug!("LD [imm16], sp");
                 let write_location = gb.read_word_and_advance_program_counter();
-                let write_byte = gb.registers.a;
-                gb.gb_memory.write_byte(write_location, write_byte);
-                return ControlFlow::Break(());
+                let sp = gb.registers.stack_pointer;
+                gb.gb_memory.write_byte(write_location, (sp & 0xFF) as u8);
+                gb.gb_memory
+                    .write_byte(write_location.wrapping_add(1), (sp >> 8) as u8);
+                return ControlFlow::Break(20);
             }
             //inc r16
             if (query_byte & 0b1111) == 0b0011 {
                 debug!("inc r16");
                 //Apparently this doesn't set any flags... :shrug:
                 let register_index = (0b00110000 & query_byte) >> 4;
-                let new_val = gb.registers.get_r16(register_index) + 1;
+                let new_val = gb.registers.get_r16(register_index).wrapping_add(1);
                 gb.registers.set_r16(register_index, new_val);
-                return ControlFlow::Break(());
+                return ControlFlow::Break(8);
             }
             //dec r16
             if (query_byte & 0b1111) == 0b1011 {
                 debug!("dec r16");
                 //Apparently this doesn't set any flags... :shrug:
                 let register_index = (0b00110000 & query_byte) >> 4;
-                let new_val = gb.registers.get_r16(register_index).saturating_sub(1);
+                let new_val = gb.registers.get_r16(register_index).wrapping_sub(1);
                 gb.registers.set_r16(register_index, new_val);
-                return ControlFlow::Break(());
+                return ControlFlow::Break(8);
             }
             //add hl, r16
             if (query_byte & 0b1111) == 0b1001 {
@@ -287,7 +679,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 gb.registers.f.c = overflow;
                 gb.registers.f.h = calculate_word_half_carry_add(old_hl, r16);
                 gb.registers.set_hl(new_value);
-                return ControlFlow::Break(());
+                return ControlFlow::Break(8);
             }
             //inc r8
             if (query_byte & 0b111) == 0b100 {
@@ -299,7 +691,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 gb.registers.f.n = false;
                 gb.registers.f.z = new_val == 0;
                 gb.registers.f.h = calculate_byte_half_carry_add(old_val, 1);
-                return ControlFlow::Break(());
+                return ControlFlow::Break(if r8_id == 6 { 12 } else { 4 });
             }
             //dec r8
             if (query_byte & 0b111) == 0b101 {
@@ -310,15 +702,15 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 gb.set_r8(r8_id, new_val);
                 gb.registers.f.n = true;
                 gb.registers.f.z = new_val == 0;
-                gb.registers.f.h = false; //TODO: Implement half-carry
-                return ControlFlow::Break(());
+                gb.registers.f.h = calculate_byte_half_carry_sub(old_val, 1);
+                return ControlFlow::Break(if r8_id == 6 { 12 } else { 4 });
             }
             if (query_byte & 0b11000111) == 0b00000110 {
                 debug!("ld r8, imm8");
                 let write_byte = gb.read_byte_and_advance_program_counter();
-                let r8_id = (query_byte & 0b0011000) >> 3;
+                let r8_id = (query_byte & 0b00111000) >> 3;
                 gb.set_r8(r8_id, write_byte);
-                return ControlFlow::Break(());
+                return ControlFlow::Break(if r8_id == 6 { 12 } else { 8 });
             }
             //jr imm8
             if query_byte == 0b00011000 {
@@ -329,7 +721,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 let current_pc = gb.registers.program_counter;
                 let new_pc = current_pc.wrapping_add_signed(offset);
                 gb.registers.program_counter = new_pc;
-                return ControlFlow::Break(());
+                return ControlFlow::Break(12);
             }
             //jr cond, imm8
             //Has to be checked after checking for JR imm8 because that is just a special
@@ -343,15 +735,17 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 let condition_id = (query_byte & 0b00011000) >> 3;
                 if gb.registers.f.check_condition(condition_id) {
                     let new_pc = current_pc.wrapping_add_signed(offset);
-                    gb.registers.program_counter = new_pc
+                    gb.registers.program_counter = new_pc;
+                    return ControlFlow::Break(12);
                 }
-                return ControlFlow::Break(());
+                return ControlFlow::Break(8);
             }
             //stop
-            //TODO: implement CPU mode switching if I later decide to support gbc games
             if query_byte == 0b00010000 {
+                debug!("stop");
                 let _ = gb.read_byte_and_advance_program_counter(); // pull but is unused
-                return ControlFlow::Break(());
+                gb.stop();
+                return ControlFlow::Break(4);
             }
             match query_byte {
                 0b111 => {
@@ -361,7 +755,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     let new_val = reg_a.rotate_left(1);
                     gb.registers.f.c = (0b10000000 & reg_a) > 0;
                     gb.registers.a = new_val;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(4);
                 }
                 0b1111 => {
                     debug!("rrca");
@@ -370,33 +764,58 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     let new_val = reg_a.rotate_right(1);
                     gb.registers.f.c = (0b1 & reg_a) > 0;
                     gb.registers.a = new_val;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(4);
                 }
                 0b10111 => {
                     debug!("rla");
                     let old_c = gb.registers.f.c;
                     let reg_a = gb.registers.a;
                     gb.registers.f.set_as_f_register(0);
-                    let (mut new_a, overflow) = reg_a.overflowing_shl(1);
-                    new_a |= if old_c { 0b1 } else { 0b0 };
+                    let new_a = (reg_a << 1) | if old_c { 0b1 } else { 0b0 };
                     gb.registers.a = new_a;
-                    gb.registers.f.c = overflow;
-                    return ControlFlow::Break(());
+                    gb.registers.f.c = (reg_a & 0b10000000) > 0;
+                    return ControlFlow::Break(4);
                 }
                 0b11111 => {
                     debug!("rra");
                     let old_c = gb.registers.f.c;
                     let reg_a = gb.registers.a;
                     gb.registers.f.set_as_f_register(0);
-                    let (mut new_a, overflow) = reg_a.overflowing_shr(1);
-                    new_a |= if old_c { 0b10000000 } else { 0b0 };
+                    let new_a = (reg_a >> 1) | if old_c { 0b10000000 } else { 0b0 };
                     gb.registers.a = new_a;
-                    gb.registers.f.c = overflow;
-                    return ControlFlow::Break(());
+                    gb.registers.f.c = (reg_a & 0b1) > 0;
+                    return ControlFlow::Break(4);
                 }
                 0b100111 => {
                     debug!("daa");
-                    unimplemented!("DAA not implemented presently...")
+                    //Fixes a back up into BCD after an add or sub of two BCD numbers.  N tells us
+                    //which one it was, H and C tell us which digits overflowed/borrowed
+                    let old_a = gb.registers.a;
+                    let mut adjustment = 0u8;
+                    let mut carry = gb.registers.f.c;
+                    let new_a = if !gb.registers.f.n {
+                        if gb.registers.f.c || old_a > 0x99 {
+                            adjustment |= 0x60;
+                            carry = true;
+                        }
+                        if gb.registers.f.h || (old_a & 0x0F) > 0x09 {
+                            adjustment |= 0x06;
+                        }
+                        old_a.wrapping_add(adjustment)
+                    } else {
+                        if gb.registers.f.c {
+                            adjustment |= 0x60;
+                        }
+                        if gb.registers.f.h {
+                            adjustment |= 0x06;
+                        }
+                        old_a.wrapping_sub(adjustment)
+                    };
+                    gb.registers.a = new_a;
+                    gb.registers.f.z = new_a == 0;
+                    gb.registers.f.h = false;
+                    gb.registers.f.c = carry;
+                    return ControlFlow::Break(4);
                 }
                 0b101111 => {
                     debug!("cpl");
@@ -404,20 +823,20 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.f.n = true;
                     gb.registers.f.h = true;
                     gb.registers.a = !old_a;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(4);
                 }
                 0b110111 => {
                     debug!("scf");
                     gb.registers.f.n = false;
                     gb.registers.f.h = false;
                     gb.registers.f.c = true;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(4);
                 }
                 0b111111 => {
                     debug!("ccf");
                     let old_carry = gb.registers.f.c;
                     gb.registers.f.c = !old_carry;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(4);
                 }
                 _ => (),
             }
@@ -426,7 +845,8 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
             //halt
             if query_byte == 0b01110110 {
                 debug!("halt");
-                panic!("Halt called");
+                gb.halt();
+                return ControlFlow::Break(4);
             }
             //ld r8, r8
             debug!("ld r8, r8");
@@ -434,7 +854,11 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
             let src_r8_id = query_byte & 0b111;
             let write_byte = gb.get_r8(src_r8_id);
             gb.set_r8(dest_r8_id, write_byte);
-            return ControlFlow::Break(());
+            return ControlFlow::Break(if dest_r8_id == 6 || src_r8_id == 6 {
+                8
+            } else {
+                4
+            });
         }
         0b10 => {
             let operand_id = query_byte & 0b111;
@@ -451,7 +875,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.f.h =
                         calculate_byte_half_carry_add(original_operand_value, original_a);
                     gb.registers.a = new_value;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(if operand_id == 6 { 8 } else { 4 });
                 }
                 0b10001 => {
                     debug!("adc a, r8");
@@ -466,27 +890,34 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     );
                     gb.registers.f.c = overflow;
                     gb.registers.a = new_value;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(if operand_id == 6 { 8 } else { 4 });
                 }
                 0b10010 => {
                     debug!("sub a, r8");
                     let (new_value, overflow) = original_a.overflowing_sub(original_operand_value);
                     gb.registers.f.n = true;
                     gb.registers.f.z = new_value == 0;
-                    gb.registers.f.h = false; //TODO: implement half carry
+                    gb.registers.f.h =
+                        calculate_byte_half_carry_sub(original_a, original_operand_value);
                     gb.registers.f.c = overflow;
                     gb.registers.a = new_value;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(if operand_id == 6 { 8 } else { 4 });
                 }
                 0b10011 => {
                     debug!("sbc a, r8");
-                    let (new_value, overflow) = original_a.overflowing_sub(original_operand_value);
+                    let old_c = gb.registers.f.c;
+                    let carry_sub = if old_c { 0b1 } else { 0b0 };
+                    let new_value = original_a
+                        .wrapping_sub(original_operand_value)
+                        .wrapping_sub(carry_sub);
                     gb.registers.f.n = true;
                     gb.registers.f.z = new_value == 0;
-                    gb.registers.f.h = false; //TODO: implement half carry
-                    gb.registers.f.c = overflow;
+                    gb.registers.f.h =
+                        calculate_byte_half_carry_sbc(original_a, original_operand_value, old_c);
+                    gb.registers.f.c =
+                        calculate_byte_carry_sbc(original_a, original_operand_value, old_c);
                     gb.registers.a = new_value;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(if operand_id == 6 { 8 } else { 4 });
                 }
                 0b10100 => {
                     debug!("and a, r8");
@@ -496,7 +927,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.f.h = true;
                     gb.registers.f.c = false;
                     gb.registers.f.z = new_value == 0;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(if operand_id == 6 { 8 } else { 4 });
                 }
                 0b10101 => {
                     debug!("xor a, r8");
@@ -506,7 +937,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.f.h = false;
                     gb.registers.f.c = false;
                     gb.registers.f.z = new_value == 0;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(if operand_id == 6 { 8 } else { 4 });
                 }
                 0b10110 => {
                     debug!("or a, r8");
@@ -516,16 +947,17 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.f.h = false;
                     gb.registers.f.c = false;
                     gb.registers.f.z = new_value == 0;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(if operand_id == 6 { 8 } else { 4 });
                 }
                 0b10111 => {
                     debug!("cp a, r8");
                     let (result, _) = original_a.overflowing_sub(original_operand_value);
                     gb.registers.f.n = true;
                     gb.registers.f.z = result == 0;
-                    gb.registers.f.h = false; //TODO: Guess what! Still need to implement h/c
+                    gb.registers.f.h =
+                        calculate_byte_half_carry_sub(original_a, original_operand_value);
                     gb.registers.f.c = original_operand_value > original_a;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(if operand_id == 6 { 8 } else { 4 });
                 }
                 _ => (),
             }
@@ -541,8 +973,9 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 if cond_state {
                     let new_pc = gb.pop_stack_word();
                     gb.registers.program_counter = new_pc;
+                    return ControlFlow::Break(20);
                 }
-                return ControlFlow::Break(());
+                return ControlFlow::Break(8);
             }
             if (query_byte & 0b11100111) == 0b11000010 {
                 debug!("jp cond, imm16");
@@ -551,8 +984,9 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 let cond_state = gb.registers.f.check_condition(cond_id);
                 if cond_state {
                     gb.registers.program_counter = jp_location;
+                    return ControlFlow::Break(16);
                 }
-                return ControlFlow::Break(());
+                return ControlFlow::Break(12);
             }
             if (query_byte & 0b11100111) == 0b11000100 {
                 debug!("call cond, imm16");
@@ -563,8 +997,9 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 if cond_state {
                     gb.push_stack_word(return_pc);
                     gb.registers.program_counter = call_location;
+                    return ControlFlow::Break(24);
                 }
-                return ControlFlow::Break(());
+                return ControlFlow::Break(12);
             }
             if (query_byte & 0b11000111) == 0b11000111 {
                 debug!("rst vec");
@@ -573,7 +1008,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 let return_pc = gb.registers.program_counter;
                 gb.push_stack_word(return_pc);
                 gb.registers.program_counter = vec;
-                return ControlFlow::Break(());
+                return ControlFlow::Break(16);
             }
             if (query_byte & 0b11001111) == 0b11000001 {
                 debug!("pop r16stk");
@@ -581,14 +1016,14 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                 // let r16 = gb.registers.get_r16stk(r16stk_id);
                 let read_word = gb.pop_stack_word();
                 gb.registers.set_r16stk(r16stk_id, read_word);
-                return ControlFlow::Break(());
+                return ControlFlow::Break(12);
             }
             if (query_byte & 0b11001111) == 0b11000101 {
                 debug!("push r16stk");
                 let r16stk_id = (query_byte & 0b00110000) >> 4;
                 let r16 = gb.registers.get_r16stk(r16stk_id);
                 gb.push_stack_word(r16);
-                return ControlFlow::Break(());
+                return ControlFlow::Break(16);
             }
 
             match query_byte {
@@ -602,7 +1037,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.f.h = false; //TODO: implement h/c
                     gb.registers.f.c = overflow;
                     gb.registers.a = result;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11001110 => {
                     debug!("adc a, imm8");
@@ -616,7 +1051,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                         calculate_byte_half_carry_add(old_a, next_byte + carry_addition);
                     gb.registers.f.c = overflow;
                     gb.registers.a = result;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11010110 => {
                     debug!("sub a, imm8");
@@ -625,23 +1060,24 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     let (result, overflow) = old_a.overflowing_sub(next_byte);
                     gb.registers.f.z = result == 0;
                     gb.registers.f.n = true;
-                    gb.registers.f.h = false; //TODO: implement h/c
+                    gb.registers.f.h = calculate_byte_half_carry_sub(old_a, next_byte);
                     gb.registers.f.c = overflow;
                     gb.registers.a = result;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11011110 => {
                     debug!("sbc a, imm8");
                     let old_a = gb.registers.a;
                     let next_byte = gb.read_byte_and_advance_program_counter();
-                    let carry_sub = if gb.registers.f.c { 0b1 } else { 0b0 };
-                    let (result, overflow) = old_a.overflowing_sub(next_byte + carry_sub);
+                    let old_c = gb.registers.f.c;
+                    let carry_sub = if old_c { 0b1 } else { 0b0 };
+                    let result = old_a.wrapping_sub(next_byte).wrapping_sub(carry_sub);
                     gb.registers.f.z = result == 0;
                     gb.registers.f.n = true;
-                    gb.registers.f.h = false; //TODO: implement h/c
-                    gb.registers.f.c = overflow;
+                    gb.registers.f.h = calculate_byte_half_carry_sbc(old_a, next_byte, old_c);
+                    gb.registers.f.c = calculate_byte_carry_sbc(old_a, next_byte, old_c);
                     gb.registers.a = result;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11100110 => {
                     debug!("and a, imm8");
@@ -653,7 +1089,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.f.n = false;
                     gb.registers.f.h = true;
                     gb.registers.f.c = false;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11101110 => {
                     debug!("xor a, imm8");
@@ -665,7 +1101,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.f.n = false;
                     gb.registers.f.h = false;
                     gb.registers.f.c = false;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11110110 => {
                     debug!("or a, imm8");
@@ -677,7 +1113,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.f.n = false;
                     gb.registers.f.h = false;
                     gb.registers.f.c = false;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11111110 => {
                     debug!("cp a, imm8");
@@ -686,66 +1122,67 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     let (result, overflow) = old_a.overflowing_sub(next_byte);
                     gb.registers.f.z = result == 0;
                     gb.registers.f.n = true;
-                    gb.registers.f.h = false; //TODO: implement h/c
+                    gb.registers.f.h = calculate_byte_half_carry_sub(old_a, next_byte);
                     gb.registers.f.c = overflow;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11001001 => {
                     debug!("ret");
                     let new_pc = gb.pop_stack_word();
                     gb.registers.program_counter = new_pc;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(16);
                 }
                 0b11011001 => {
                     debug!("reti");
+                    //unlike ei, this takes effect immediately
                     gb.interrupt_master_flag = true;
                     let new_pc = gb.pop_stack_word();
                     gb.registers.program_counter = new_pc;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(16);
                 }
                 0b11000011 => {
                     debug!("jp imm16");
                     let new_pc = gb.read_word_and_advance_program_counter();
                     gb.registers.program_counter = new_pc;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(16);
                 }
                 0b11101001 => {
                     debug!("jp hl");
                     let new_pc = gb.registers.get_hl();
                     gb.registers.program_counter = new_pc;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(4);
                 }
                 0b11001101 => {
                     debug!("call imm16");
-                    let return_pc = gb.registers.program_counter; // I believe that it will be
-                    // already increased by 1 at this point, so this is the value we need to
-                    // add to the stack
-                    gb.push_stack_word(return_pc);
                     let new_pc = gb.read_word_and_advance_program_counter();
+                    // the return address is the instruction after the imm16, so the operand has
+                    // to be pulled before we push
+                    let return_pc = gb.registers.program_counter;
+                    gb.push_stack_word(return_pc);
                     gb.registers.program_counter = new_pc;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(24);
                 }
                 0b11100010 => {
                     debug!("ldh [$FF00 + c], a");
                     let write_byte = gb.registers.a;
                     let write_byte_location = gb.registers.c as u16 + 0xFF00;
                     gb.gb_memory.write_byte(write_byte_location, write_byte);
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11100000 => {
                     debug!("ldh [imm8], a");
                     let write_byte = gb.registers.a;
-                    let write_byte_location_low = gb.registers.c as u16;
+                    let write_byte_location_low = gb.read_byte_and_advance_program_counter() as u16;
                     let write_byte_location = 0xFF00u16 | write_byte_location_low;
                     gb.gb_memory.write_byte(write_byte_location, write_byte);
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(12);
                 }
                 0b11101010 => {
                     debug!("ld [imm16], a");
                     let write_byte = gb.registers.a;
                     let write_location = gb.read_word_and_advance_program_counter();
                     gb.gb_memory.write_byte(write_location, write_byte);
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(16);
                 }
                 0b11110010 => {
                     debug!("ldh a, [$FF00+c]");
@@ -753,7 +1190,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     let read_location = 0xFF00u16 | read_location_low;
                     let read_byte = gb.gb_memory.read_byte(read_location);
                     gb.registers.a = read_byte;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11110000 => {
                     debug!("ldh a, [imm8]");
@@ -761,14 +1198,14 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     let read_location = 0xFF00u16 | read_location_low;
                     let read_byte = gb.gb_memory.read_byte(read_location);
                     gb.registers.a = read_byte;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(12);
                 }
                 0b11111010 => {
                     debug!("ld a, [imm16]");
                     let imm16 = gb.read_word_and_advance_program_counter();
                     let write_byte = gb.gb_memory.read_byte(imm16);
                     gb.registers.a = write_byte;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(16);
                 }
                 0b11101000 => {
                     debug!("add sp, imm8");
@@ -780,7 +1217,7 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.stack_pointer = new_sp;
                     gb.registers.f.z = false;
                     gb.registers.f.n = false;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(16);
                 }
                 0b11111000 => {
                     debug!("ld hl,sp+imm8");
@@ -792,23 +1229,31 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
                     gb.registers.set_hl(new_hl);
                     gb.registers.f.z = false;
                     gb.registers.f.n = false;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(12);
                 }
                 0b11111001 => {
                     debug!("ld sp, hl");
                     let read_byte = gb.registers.get_hl();
                     gb.registers.stack_pointer = read_byte;
-                    return ControlFlow::Break(());
+                    return ControlFlow::Break(8);
                 }
                 0b11110011 => {
                     debug!("di");
                     gb.interrupt_master_flag = false;
-                    return ControlFlow::Break(());
+                    gb.interrupt_enable_pending = false;
+                    return ControlFlow::Break(4);
                 }
                 0b11111011 => {
                     debug!("ei");
-                    gb.interrupt_master_flag = true;
-                    return ControlFlow::Break(());
+                    //delayed by one instruction, see run_next_op
+                    gb.interrupt_enable_pending = true;
+                    return ControlFlow::Break(4);
+                }
+                0b11001011 => {
+                    debug!("prefix cb");
+                    let cb_byte = gb.read_byte_and_advance_program_counter();
+                    debug!("0xcb 0x{:02x}", cb_byte);
+                    return execute_cb_op(gb, cb_byte);
                 }
                 _ => (),
             }
@@ -819,27 +1264,546 @@ fn execute_op(gb: &mut gameboy::Gb, query_byte: u8) -> ControlFlow<()> {
     }
     ControlFlow::Continue(())
 }
-fn read_rom(gb_memory: &mut gb_memory::GbMemory) {
-    let mut contents = fs::read("tetris.gb").expect("Unable to read test rom.");
-    // println!("{:#?}", contents);
-    let cart_title = std::str::from_utf8(&contents[0x134..0x143])
-        .expect("Improperly formatted ROM Header (Title)");
-    info!("Game Title: {}", cart_title);
-    let cart_type = contents[0x147];
-    info!("Cartridge Type: 0x{:02x}", cart_type);
-    let cart_rom_size_type = contents[0x148];
-    info!("ROM Size Type: 0x{:02x}", cart_rom_size_type);
-    let cart_ram_size_type = contents[0x149];
-    info!("RAM Size Type: 0x{:02x}", cart_ram_size_type);
-    let cart_destination_code = contents[0x014A];
-    info!(
-        "Cartridge Destination: {}",
-        if cart_destination_code > 0 {
-            "Overseas Only"
-        } else {
-            "Japan (or possibly overseas)"
+/// Same as `execute_op` but for the byte following a 0xCB prefix.  The returned T-cycles include
+/// the prefix fetch
+fn execute_cb_op(gb: &mut gameboy::Gb, cb_byte: u8) -> ControlFlow<u32> {
+    //Every CB opcode is laid out as [op:2][bit index / sub-op:3][r8:3], so the operand is
+    //always decoded the same way and [hl] goes thru get_r8/set_r8 like everything else
+    let operand_id = cb_byte & 0b111;
+    let bit_index = (cb_byte & 0b00111000) >> 3;
+    let original_value = gb.get_r8(operand_id);
+    match cb_byte >> 6 {
+        0b00 => {
+            let old_c = gb.registers.f.c;
+            let (new_value, carry) = match bit_index {
+                0b000 => {
+                    debug!("rlc r8");
+                    (
+                        original_value.rotate_left(1),
+                        (original_value & 0b10000000) > 0,
+                    )
+                }
+                0b001 => {
+                    debug!("rrc r8");
+                    (original_value.rotate_right(1), (original_value & 0b1) > 0)
+                }
+                0b010 => {
+                    debug!("rl r8");
+                    let carry_in = if old_c { 0b1 } else { 0b0 };
+                    (
+                        (original_value << 1) | carry_in,
+                        (original_value & 0b10000000) > 0,
+                    )
+                }
+                0b011 => {
+                    debug!("rr r8");
+                    let carry_in = if old_c { 0b10000000 } else { 0b0 };
+                    ((original_value >> 1) | carry_in, (original_value & 0b1) > 0)
+                }
+                0b100 => {
+                    debug!("sla r8");
+                    (original_value << 1, (original_value & 0b10000000) > 0)
+                }
+                0b101 => {
+                    debug!("sra r8");
+                    //arithmetic shift, bit 7 stays put
+                    (
+                        (original_value >> 1) | (original_value & 0b10000000),
+                        (original_value & 0b1) > 0,
+                    )
+                }
+                0b110 => {
+                    debug!("swap r8");
+                    (original_value.rotate_left(4), false)
+                }
+                0b111 => {
+                    debug!("srl r8");
+                    (original_value >> 1, (original_value & 0b1) > 0)
+                }
+                _ => unreachable!(),
+            };
+            gb.set_r8(operand_id, new_value);
+            gb.registers.f.z = new_value == 0;
+            gb.registers.f.n = false;
+            gb.registers.f.h = false;
+            gb.registers.f.c = carry;
         }
-    );
-    contents.resize(0xFFFF + 1, 0u8);
-    gb_memory.memory_array.copy_from_slice(&contents);
+        0b01 => {
+            debug!("bit b3, r8");
+            //carry is left alone
+            gb.registers.f.z = (original_value & (1 << bit_index)) == 0;
+            gb.registers.f.n = false;
+            gb.registers.f.h = true;
+            //bit only reads [hl], so it skips the write-back cycle
+            return ControlFlow::Break(if operand_id == 6 { 12 } else { 8 });
+        }
+        0b10 => {
+            debug!("res b3, r8");
+            gb.set_r8(operand_id, original_value & !(1 << bit_index));
+        }
+        0b11 => {
+            debug!("set b3, r8");
+            gb.set_r8(operand_id, original_value | (1 << bit_index));
+        }
+        _ => unreachable!(),
+    }
+    ControlFlow::Break(if operand_id == 6 { 16 } else { 8 })
+}
+/// Reads and validates a ROM, exiting with an error if it's malformed.  A bad header checksum is
+/// only fatal when running the boot ROM, which would lock up on it.  Without one it's most likely
+/// homebrew or a patched ROM, so it only gets logged
+fn read_rom(rom_path: &Path, boot_rom_in_use: bool) -> cartridge::Cartridge {
+    let contents = match fs::read(rom_path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            error!("Unable to read ROM {}: {}", rom_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let header = match cartridge_header::CartridgeHeader::parse(&contents) {
+        Ok(header) => header,
+        Err(e) => {
+            error!("Refusing to load {}: {}", rom_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    for line in header.to_string().lines() {
+        info!("{}", line);
+    }
+    if let Err(e) = header.verify_header_checksum(&contents) {
+        if boot_rom_in_use {
+            error!("Refusing to load {}: {}", rom_path.display(), e);
+            std::process::exit(1);
+        }
+        error!("{}, real hardware wouldn't boot this", e);
+    }
+    if let Err(e) = header.verify_global_checksum(&contents) {
+        warn!("{}, the dump might be bad", e);
+    }
+    if contents.len() != header.rom_size {
+        warn!(
+            "ROM is {} bytes but the header says it should be {}",
+            contents.len(),
+            header.rom_size
+        );
+    }
+    match cartridge::Cartridge::new(contents, header) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            error!("Refusing to load {}: {}", rom_path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+fn read_boot_rom(boot_rom_path: &Path) -> Vec<u8> {
+    let contents = match fs::read(boot_rom_path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            error!("Unable to read boot ROM {}: {}", boot_rom_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    //256 bytes for dmg/mgb, 2304 for cgb
+    if contents.len() != 0x100 && contents.len() != 0x900 {
+        error!(
+            "Boot ROM {} is {} bytes, expected 256 (DMG) or 2304 (CGB)",
+            boot_rom_path.display(),
+            contents.len()
+        );
+        std::process::exit(1);
+    }
+    info!("Running boot ROM {}", boot_rom_path.display());
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_state::tests::test_machine;
+
+    const ADD_A_B: u8 = 0x80;
+    const SUB_A_B: u8 = 0x90;
+    const SBC_A_B: u8 = 0x98;
+    const DAA: u8 = 0x27;
+    const NOP: u8 = 0x00;
+    const DI: u8 = 0xF3;
+    const EI: u8 = 0xFB;
+    const HALT: u8 = 0x76;
+    const STOP: u8 = 0x10;
+    const INC_A: u8 = 0x3C;
+
+    const PROGRAM_START: u16 = 0xC000;
+
+    // where (HL) points in the tests that use it
+    const HL_TARGET: u16 = 0xC100;
+
+    /// A test machine about to run `program` from work RAM
+    fn machine_running(program: &[u8]) -> gameboy::Gb {
+        let mut gb = test_machine(None);
+        for (offset, byte) in program.iter().enumerate() {
+            gb.gb_memory
+                .write_byte(PROGRAM_START + offset as u16, *byte);
+        }
+        gb.registers.program_counter = PROGRAM_START;
+        gb
+    }
+
+    /// Runs `opcode` on A and B, then DAA
+    fn bcd_op(opcode: u8, a: u8, b: u8) -> gameboy::Gb {
+        let mut gb = test_machine(None);
+        gb.registers.a = a;
+        gb.registers.b = b;
+        gb.registers.f.set_as_f_register(0);
+        assert_eq!(execute_op(&mut gb, opcode), ControlFlow::Break(4));
+        assert_eq!(execute_op(&mut gb, DAA), ControlFlow::Break(4));
+        gb
+    }
+
+    #[test]
+    fn half_carry_helpers() {
+        assert!(calculate_byte_half_carry_add(0x0F, 0x01));
+        assert!(!calculate_byte_half_carry_add(0x0E, 0x01));
+        assert!(calculate_word_half_carry_add(0x00FF, 0x0001));
+        assert!(!calculate_word_half_carry_add(0x0FFE, 0x0001));
+        assert!(calculate_byte_half_carry_sub(0x10, 0x01));
+        assert!(!calculate_byte_half_carry_sub(0x1F, 0x0F));
+        assert!(calculate_byte_half_carry_sbc(0x10, 0x0F, false));
+        assert!(calculate_byte_half_carry_sbc(0x1F, 0x0F, true));
+        assert!(!calculate_byte_half_carry_sbc(0x1F, 0x0F, false));
+        assert!(calculate_byte_carry_sbc(0x10, 0x10, true));
+        assert!(!calculate_byte_carry_sbc(0x10, 0x0F, true));
+    }
+
+    #[test]
+    fn sbc_uses_incoming_carry() {
+        let mut gb = test_machine(None);
+        gb.registers.a = 0x10;
+        gb.registers.b = 0x0F;
+        gb.registers.f.set_as_f_register(0);
+        gb.registers.f.c = true;
+        assert_eq!(execute_op(&mut gb, SBC_A_B), ControlFlow::Break(4));
+        assert_eq!(gb.registers.a, 0x00);
+        assert!(gb.registers.f.z && gb.registers.f.n && gb.registers.f.h);
+        assert!(!gb.registers.f.c);
+    }
+
+    #[test]
+    fn daa_after_add() {
+        //0x09 + 0x08 = 0x11 in binary, which only has the half carry to go on
+        let gb = bcd_op(ADD_A_B, 0x09, 0x08);
+        assert_eq!(gb.registers.a, 0x17);
+        assert!(!gb.registers.f.c);
+        let gb = bcd_op(ADD_A_B, 0x45, 0x38);
+        assert_eq!(gb.registers.a, 0x83);
+        //99 + 1 wraps to 00 with a carry out
+        let gb = bcd_op(ADD_A_B, 0x99, 0x01);
+        assert_eq!(gb.registers.a, 0x00);
+        assert!(gb.registers.f.z && gb.registers.f.c && !gb.registers.f.h);
+        let gb = bcd_op(ADD_A_B, 0x90, 0x90);
+        assert_eq!(gb.registers.a, 0x80);
+        assert!(gb.registers.f.c);
+    }
+
+    #[test]
+    fn daa_after_sub() {
+        let gb = bcd_op(SUB_A_B, 0x10, 0x01);
+        assert_eq!(gb.registers.a, 0x09);
+        assert!(gb.registers.f.n && !gb.registers.f.c);
+        let gb = bcd_op(SUB_A_B, 0x42, 0x42);
+        assert_eq!(gb.registers.a, 0x00);
+        assert!(gb.registers.f.z);
+        //00 - 01 borrows, leaving 99
+        let gb = bcd_op(SUB_A_B, 0x00, 0x01);
+        assert_eq!(gb.registers.a, 0x99);
+        assert!(gb.registers.f.c);
+    }
+
+    #[test]
+    fn ei_waits_for_the_next_instruction() {
+        let mut gb = machine_running(&[EI, NOP, NOP]);
+        run_next_op(&mut gb);
+        assert!(!gb.interrupt_master_flag);
+        run_next_op(&mut gb);
+        assert!(gb.interrupt_master_flag);
+        assert!(!gb.interrupt_enable_pending);
+    }
+
+    #[test]
+    fn di_cancels_a_pending_ei() {
+        let mut gb = machine_running(&[EI, DI, NOP]);
+        run_next_op(&mut gb);
+        run_next_op(&mut gb);
+        assert!(!gb.interrupt_master_flag);
+        run_next_op(&mut gb);
+        assert!(!gb.interrupt_master_flag);
+    }
+
+    #[test]
+    fn interrupts_serviced_in_priority_order() {
+        let mut gb = machine_running(&[]);
+        //nothing happens with IME off
+        gb.gb_memory.write_byte(0xFFFF, 0b11110);
+        gb.gb_memory.write_byte(0xFF0F, 0b10101);
+        assert_eq!(check_interrupts(&mut gb), 0);
+        //v-blank is requested but not enabled, so timer goes first
+        for (vector, flags_left) in [(0x50, 0b10001), (0x60, 0b00001)] {
+            gb.interrupt_master_flag = true;
+            assert_eq!(check_interrupts(&mut gb), 20);
+            assert_eq!(gb.registers.program_counter, vector);
+            assert!(!gb.interrupt_master_flag);
+            assert_eq!(gb.gb_memory.read_byte(0xFF0F) & 0b11111, flags_left);
+        }
+        gb.interrupt_master_flag = true;
+        assert_eq!(check_interrupts(&mut gb), 0);
+        //the return address went on the stack
+        assert_eq!(gb.pop_stack_word(), 0x50);
+        assert_eq!(gb.pop_stack_word(), PROGRAM_START);
+    }
+
+    #[test]
+    fn halt_bug_runs_the_next_byte_twice() {
+        let mut gb = machine_running(&[HALT, INC_A, NOP]);
+        gb.registers.a = 0;
+        //IME off with an interrupt already waiting
+        gb.gb_memory.write_byte(0xFFFF, 0b00100);
+        gb.gb_memory.write_byte(0xFF0F, 0b00100);
+        step_cpu(&mut gb);
+        assert!(!gb.halted);
+        for address in [PROGRAM_START + 1, PROGRAM_START + 1, PROGRAM_START + 2] {
+            assert_eq!(
+                step_cpu(&mut gb),
+                CpuStep::Op {
+                    address,
+                    opcode: gb.gb_memory.read_byte(address)
+                }
+            );
+        }
+        assert_eq!(gb.registers.a, 2);
+    }
+
+    #[test]
+    fn halt_wakes_without_ime() {
+        let mut gb = machine_running(&[HALT, INC_A]);
+        gb.gb_memory.write_byte(0xFFFF, 0b00100);
+        step_cpu(&mut gb);
+        assert!(gb.halted);
+        assert_eq!(step_cpu(&mut gb), CpuStep::Halted);
+        assert_eq!(step_cpu(&mut gb), CpuStep::Halted);
+        //the interrupt wakes it, but with IME off it carries on after the HALT
+        gb.gb_memory.write_byte(0xFF0F, 0b00100);
+        assert_eq!(check_interrupts(&mut gb), 0);
+        assert_eq!(
+            step_cpu(&mut gb),
+            CpuStep::Op {
+                address: PROGRAM_START + 1,
+                opcode: INC_A
+            }
+        );
+        assert!(!gb.halted);
+        //and the request is still there
+        assert_eq!(gb.gb_memory.read_byte(0xFF0F) & 0b11111, 0b00100);
+    }
+
+    #[test]
+    fn halt_with_ime_goes_to_the_handler() {
+        let mut gb = machine_running(&[HALT, INC_A]);
+        gb.interrupt_master_flag = true;
+        gb.gb_memory.write_byte(0xFFFF, 0b00100);
+        step_cpu(&mut gb);
+        assert!(gb.halted);
+        gb.gb_memory.write_byte(0xFF0F, 0b00100);
+        assert_eq!(check_interrupts(&mut gb), 20);
+        assert!(!gb.halted);
+        assert_eq!(gb.registers.program_counter, 0x50);
+        //returning from the handler lands after the HALT
+        assert_eq!(gb.pop_stack_word(), PROGRAM_START + 1);
+    }
+
+    #[test]
+    fn stop_resets_div_and_waits_for_a_button() {
+        let mut gb = machine_running(&[STOP, 0x00, INC_A]);
+        gb.tick(0x400);
+        assert_eq!(gb.gb_memory.read_byte(0xFF04), 0x04);
+        //the buttons are selected so a press can pull a line low
+        gb.gb_memory.write_byte(0xFF00, 0b00010000);
+        step_cpu(&mut gb);
+        assert!(gb.stopped);
+        assert_eq!(gb.gb_memory.read_byte(0xFF04), 0);
+        assert_eq!(step_cpu(&mut gb), CpuStep::Stopped);
+        //nothing moves while stopped
+        assert_eq!(gb.gb_memory.read_byte(0xFF04), 0);
+        gb.gb_memory
+            .set_button_pressed(joypad::JoypadButton::A, true);
+        assert_eq!(
+            step_cpu(&mut gb),
+            CpuStep::Op {
+                address: PROGRAM_START + 2,
+                opcode: INC_A
+            }
+        );
+        assert!(!gb.stopped);
+    }
+
+    /// T-cycles taken by the first instruction of `program`, run with Z as given
+    fn cycles_for(program: &[u8], z: bool) -> u32 {
+        let mut gb = machine_running(program);
+        gb.registers.f.z = z;
+        gb.registers.set_hl(HL_TARGET);
+        run_next_op(&mut gb).2
+    }
+
+    #[test]
+    fn conditional_cycle_counts() {
+        //the NZ forms, so taken with Z clear
+        for (program, taken, not_taken) in [
+            (&[0x20, 0x05][..], 12, 8),        // JR NZ, e8
+            (&[0xC2, 0x00, 0xC1][..], 16, 12), // JP NZ, a16
+            (&[0xC4, 0x00, 0xC1][..], 24, 12), // CALL NZ, a16
+            (&[0xC0][..], 20, 8),              // RET NZ
+        ] {
+            assert_eq!(cycles_for(program, false), taken, "{:02x?} taken", program);
+            assert_eq!(
+                cycles_for(program, true),
+                not_taken,
+                "{:02x?} not taken",
+                program
+            );
+        }
+    }
+
+    #[test]
+    fn unconditional_cycle_counts() {
+        for (program, cycles) in [
+            (&[0x18, 0x05][..], 12),       // JR e8
+            (&[0xC3, 0x00, 0xC1][..], 16), // JP a16
+            (&[0xE9][..], 4),              // JP HL
+            (&[0xCD, 0x00, 0xC1][..], 24), // CALL a16
+            (&[0xC9][..], 16),             // RET
+            (&[0xD9][..], 16),             // RETI
+            (&[0xFF][..], 16),             // RST 38
+            (&[0xC5][..], 16),             // PUSH BC
+            (&[0xC1][..], 12),             // POP BC
+            (&[0x36, 0x12][..], 12),       // LD (HL), n8
+            (&[0x34][..], 12),             // INC (HL)
+            (&[0x08, 0x00, 0xC1][..], 20), // LD (a16), SP
+            (&[0xE8, 0x01][..], 16),       // ADD SP, e8
+            (&[0xF8, 0x01][..], 12),       // LD HL, SP+e8
+        ] {
+            assert_eq!(cycles_for(program, false), cycles, "{:02x?}", program);
+        }
+    }
+
+    #[test]
+    fn cb_cycle_counts() {
+        for (cb_byte, cycles) in [
+            (0x00, 8),  // RLC B
+            (0x06, 16), // RLC (HL)
+            (0x40, 8),  // BIT 0, B
+            (0x46, 12), // BIT 0, (HL), which doesn't write back
+            (0x80, 8),  // RES 0, B
+            (0x86, 16), // RES 0, (HL)
+            (0xC6, 16), // SET 0, (HL)
+        ] {
+            assert_eq!(
+                cycles_for(&[0xCB, cb_byte], false),
+                cycles,
+                "CB {:02x}",
+                cb_byte
+            );
+        }
+    }
+
+    /// Runs CB `cb_byte` on B holding `value`, with N and H set beforehand to check they get
+    /// cleared
+    fn cb_on_b(cb_byte: u8, value: u8, carry: bool) -> gameboy::Gb {
+        let mut gb = machine_running(&[0xCB, cb_byte]);
+        gb.registers.b = value;
+        gb.registers.f.set_as_f_register(0);
+        gb.registers.f.n = true;
+        gb.registers.f.h = true;
+        gb.registers.f.c = carry;
+        run_next_op(&mut gb);
+        gb
+    }
+
+    #[test]
+    fn cb_rotates_and_shifts() {
+        for (name, cb_byte, value, carry_in, result, carry_out) in [
+            ("RLC", 0x00, 0x85, false, 0x0B, true),
+            ("RLC", 0x00, 0x00, true, 0x00, false),
+            ("RRC", 0x08, 0x01, false, 0x80, true),
+            ("RRC", 0x08, 0x10, true, 0x08, false),
+            ("RL", 0x10, 0x80, false, 0x00, true),
+            ("RL", 0x10, 0x11, true, 0x23, false),
+            ("RR", 0x18, 0x01, false, 0x00, true),
+            ("RR", 0x18, 0x8A, true, 0xC5, false),
+            ("SLA", 0x20, 0xFF, false, 0xFE, true),
+            ("SLA", 0x20, 0x01, true, 0x02, false),
+            ("SRA", 0x28, 0x8A, true, 0xC5, false),
+            ("SRA", 0x28, 0x01, false, 0x00, true),
+            ("SWAP", 0x30, 0xF1, true, 0x1F, false),
+            ("SWAP", 0x30, 0x00, false, 0x00, false),
+            ("SRL", 0x38, 0x81, false, 0x40, true),
+            ("SRL", 0x38, 0x80, true, 0x40, false),
+        ] {
+            let gb = cb_on_b(cb_byte, value, carry_in);
+            let flags = &gb.registers.f;
+            assert_eq!(gb.registers.b, result, "{} 0x{:02x}", name, value);
+            assert_eq!(flags.z, result == 0, "{} 0x{:02x} Z", name, value);
+            assert!(!flags.n && !flags.h, "{} 0x{:02x} N/H", name, value);
+            assert_eq!(flags.c, carry_out, "{} 0x{:02x} C", name, value);
+        }
+    }
+
+    #[test]
+    fn cb_bit_leaves_carry_alone() {
+        for carry in [false, true] {
+            //BIT 7, B
+            let gb = cb_on_b(0x78, 0x80, carry);
+            assert!(!gb.registers.f.z);
+            assert!(!gb.registers.f.n && gb.registers.f.h);
+            assert_eq!(gb.registers.f.c, carry);
+            //BIT 0, B
+            let gb = cb_on_b(0x40, 0x80, carry);
+            assert!(gb.registers.f.z);
+            assert_eq!(gb.registers.f.c, carry);
+            assert_eq!(gb.registers.b, 0x80);
+        }
+    }
+
+    #[test]
+    fn cb_res_and_set_on_hl() {
+        for (cb_byte, value, result) in [
+            (0x86, 0xFF, 0xFE), // RES 0, (HL)
+            (0xBE, 0xFF, 0x7F), // RES 7, (HL)
+            (0xC6, 0x00, 0x01), // SET 0, (HL)
+            (0xFE, 0x00, 0x80), // SET 7, (HL)
+            (0xDE, 0x08, 0x08), // SET 3, (HL), already set
+        ] {
+            let mut gb = machine_running(&[0xCB, cb_byte]);
+            gb.registers.set_hl(HL_TARGET);
+            gb.gb_memory.write_byte(HL_TARGET, value);
+            let flags_before = gb.registers.f.get_as_f_register();
+            run_next_op(&mut gb);
+            assert_eq!(
+                gb.gb_memory.read_byte(HL_TARGET),
+                result,
+                "CB {:02x}",
+                cb_byte
+            );
+            //neither touches the flags
+            assert_eq!(gb.registers.f.get_as_f_register(), flags_before);
+        }
+    }
+
+    #[test]
+    fn cb_shift_on_hl() {
+        //SRA (HL)
+        let mut gb = machine_running(&[0xCB, 0x2E]);
+        gb.registers.set_hl(HL_TARGET);
+        gb.gb_memory.write_byte(HL_TARGET, 0x81);
+        run_next_op(&mut gb);
+        assert_eq!(gb.gb_memory.read_byte(HL_TARGET), 0xC0);
+        assert!(gb.registers.f.c);
+    }
 }
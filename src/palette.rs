@@ -0,0 +1,196 @@
+use sdl2::pixels::Color;
+use std::{fs, path::Path};
+
+/// What the four dmg shades get drawn as, lightest first
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ColorScheme {
+    // the pea soup green of the original screen
+    Green,
+    // the pocket's black and white screen, which is really a few shades of grey
+    Pocket,
+    HighContrast,
+    // loaded from --palette-file
+    Custom,
+}
+
+const GREEN_COLORS: [Color; 4] = [
+    Color::RGB(0x9B, 0xBC, 0x0F),
+    Color::RGB(0x8B, 0xAC, 0x0F),
+    Color::RGB(0x30, 0x62, 0x30),
+    Color::RGB(0x0F, 0x38, 0x0F),
+];
+const POCKET_COLORS: [Color; 4] = [
+    Color::RGB(0xC4, 0xCF, 0xA1),
+    Color::RGB(0x8B, 0x95, 0x6D),
+    Color::RGB(0x4D, 0x53, 0x3C),
+    Color::RGB(0x1F, 0x1F, 0x1F),
+];
+const HIGH_CONTRAST_COLORS: [Color; 4] = [
+    Color::RGB(0xFF, 0xFF, 0xFF),
+    Color::RGB(0xAA, 0xAA, 0xAA),
+    Color::RGB(0x55, 0x55, 0x55),
+    Color::RGB(0x00, 0x00, 0x00),
+];
+
+pub(crate) struct DisplayPalette {
+    pub(crate) scheme: ColorScheme,
+    custom_colors: Option<[Color; 4]>,
+}
+
+impl DisplayPalette {
+    pub(crate) fn new(scheme: ColorScheme, custom_colors: Option<[Color; 4]>) -> Self {
+        //asking for the custom scheme without a file falls back to the default
+        let scheme = if scheme == ColorScheme::Custom && custom_colors.is_none() {
+            ColorScheme::Green
+        } else {
+            scheme
+        };
+        Self {
+            scheme,
+            custom_colors,
+        }
+    }
+    pub(crate) fn colors(&self) -> [Color; 4] {
+        match self.scheme {
+            ColorScheme::Green => GREEN_COLORS,
+            ColorScheme::Pocket => POCKET_COLORS,
+            ColorScheme::HighContrast => HIGH_CONTRAST_COLORS,
+            ColorScheme::Custom => self.custom_colors.unwrap_or(GREEN_COLORS),
+        }
+    }
+    /// Moves on to the next scheme, skipping custom if no file was loaded
+    pub(crate) fn cycle(&mut self) {
+        self.scheme = match self.scheme {
+            ColorScheme::Green => ColorScheme::Pocket,
+            ColorScheme::Pocket => ColorScheme::HighContrast,
+            ColorScheme::HighContrast if self.custom_colors.is_some() => ColorScheme::Custom,
+            ColorScheme::HighContrast | ColorScheme::Custom => ColorScheme::Green,
+        };
+    }
+}
+
+fn parse_hex_color(value: &str) -> Result<Color, String> {
+    let hex = value.trim().trim_start_matches('#');
+    //from_str_radix would let a leading + through
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Expected a color like #RRGGBB, got \"{}\"", value));
+    }
+    let rgb = u32::from_str_radix(hex, 16)
+        .map_err(|_| format!("Expected a color like #RRGGBB, got \"{}\"", value))?;
+    Ok(Color::RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+}
+
+/// Reads a palette file with one `colorN = #RRGGBB` line for each of color0 (lightest) to
+/// color3 (darkest).  Blank lines and lines starting with # are ignored
+pub(crate) fn read_palette_file(path: &Path) -> Result<[Color; 4], String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut colors = [None; 4];
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error =
+            |message: String| format!("{}:{}: {}", path.display(), line_number + 1, message);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("Expected key = value".to_owned()))?;
+        let index = match key.trim() {
+            "color0" => 0,
+            "color1" => 1,
+            "color2" => 2,
+            "color3" => 3,
+            key => return Err(error(format!("Unknown key \"{}\"", key))),
+        };
+        colors[index] = Some(parse_hex_color(value).map_err(error)?);
+    }
+    let mut palette = [Color::BLACK; 4];
+    for (index, color) in colors.iter().enumerate() {
+        palette[index] =
+            color.ok_or_else(|| format!("{}: color{} is missing", path.display(), index))?;
+    }
+    Ok(palette)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn palette_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.pal", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn read(name: &str, contents: &str) -> Result<[Color; 4], String> {
+        let path = palette_file(name, contents);
+        let result = read_palette_file(&path);
+        fs::remove_file(path).unwrap();
+        result
+    }
+
+    #[test]
+    fn hex_colors() {
+        assert_eq!(parse_hex_color("#1A2b3C"), Ok(Color::RGB(0x1A, 0x2B, 0x3C)));
+        assert_eq!(parse_hex_color(" 000000 "), Ok(Color::RGB(0, 0, 0)));
+        assert!(parse_hex_color("#FFF").is_err());
+        assert!(parse_hex_color("#GGGGGG").is_err());
+        assert!(parse_hex_color("#+12345").is_err());
+    }
+
+    #[test]
+    fn reads_a_palette_file() {
+        let colors = read(
+            "reads_a_palette_file",
+            "# lightest first\n\ncolor3 = #000000\ncolor0=#FFFFFF\n  color1 = #AAAAAA  \ncolor2 = 555555\n",
+        )
+        .unwrap();
+        assert_eq!(
+            colors,
+            [
+                Color::RGB(0xFF, 0xFF, 0xFF),
+                Color::RGB(0xAA, 0xAA, 0xAA),
+                Color::RGB(0x55, 0x55, 0x55),
+                Color::RGB(0x00, 0x00, 0x00),
+            ]
+        );
+    }
+
+    #[test]
+    fn palette_file_errors() {
+        let error = read(
+            "missing_color",
+            "color0 = #FFFFFF\ncolor1 = #AAAAAA\ncolor3 = #000000\n",
+        )
+        .unwrap_err();
+        assert!(error.ends_with("color2 is missing"));
+        let error = read("unknown_key", "color0 = #FFFFFF\ncolor4 = #000000\n").unwrap_err();
+        assert!(error.ends_with(":2: Unknown key \"color4\""));
+        let error = read("no_equals", "color0 #FFFFFF\n").unwrap_err();
+        assert!(error.ends_with(":1: Expected key = value"));
+        let error = read("bad_color", "\ncolor0 = white\n").unwrap_err();
+        assert!(error.contains(":2: Expected a color"));
+    }
+
+    #[test]
+    fn cycle_skips_custom_without_a_file() {
+        let mut palette = DisplayPalette::new(ColorScheme::Custom, None);
+        assert_eq!(palette.scheme, ColorScheme::Green);
+        for expected in [
+            ColorScheme::Pocket,
+            ColorScheme::HighContrast,
+            ColorScheme::Green,
+        ] {
+            palette.cycle();
+            assert_eq!(palette.scheme, expected);
+        }
+        let custom = [Color::RED; 4];
+        let mut palette = DisplayPalette::new(ColorScheme::HighContrast, Some(custom));
+        palette.cycle();
+        assert_eq!(palette.scheme, ColorScheme::Custom);
+        assert_eq!(palette.colors(), custom);
+        palette.cycle();
+        assert_eq!(palette.scheme, ColorScheme::Green);
+    }
+}
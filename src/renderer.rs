@@ -2,19 +2,152 @@ use log::debug;
 use sdl2::EventPump;
 use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::{Point, Rect};
-use sdl2::sys::{SDL_CreateTexture, SDL_PixelFormat, SDL_TextureAccess};
+use sdl2::sys::SDL_RenderSetVSync;
 use sdl2::video::Window;
+use std::ffi::c_int;
 
 use sdl2::render::WindowCanvas;
 
+use crate::gb_memory::GbMemory;
+use crate::palette::{ColorScheme, DisplayPalette};
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
 use crate::{GAMEBOY_HEIGHT, GAMEBOY_WIDTH};
 
+const SCY_LOCATION: u16 = 0xFF42;
+const SCX_LOCATION: u16 = 0xFF43;
+const LY_LOCATION: u16 = 0xFF44;
+const STAT_LOCATION: u16 = 0xFF41;
+const LYC_LOCATION: u16 = 0xFF45;
+const WY_LOCATION: u16 = 0xFF4A;
+const WX_LOCATION: u16 = 0xFF4B;
+// WX is the window's screen x plus 7
+const WX_OFFSET: u8 = 7;
+const BGP_LOCATION: u16 = 0xFF47;
+const OBP0_LOCATION: u16 = 0xFF48;
+const OBP1_LOCATION: u16 = 0xFF49;
+
+const OAM_LOCATION: usize = 0xFE00;
+const OAM_OBJECT_COUNT: usize = 40;
+const MAX_OBJECTS_PER_LINE: usize = 10;
+// displays close enough to the gameboy's 59.7Hz that vsync can pace the emulator
+const VSYNC_REFRESH_RATES: [i32; 2] = [59, 60];
+// object y is the screen y plus 16 and x is the screen x plus 8, so they can sit partly off
+// the top and left edges
+const OBJECT_Y_OFFSET: i16 = 16;
+const OBJECT_X_OFFSET: i16 = 8;
+
+// 3x5 pixel glyphs for the on-screen indicators, one row per byte with bit 2 leftmost
+const FONT_GLYPH_WIDTH: i32 = 3;
+const FONT_GLYPH_HEIGHT: i32 = 5;
+fn font_glyph(character: char) -> [u8; 5] {
+    match character {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b011, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        _ => [0b000; 5],
+    }
+}
+
+const DOTS_PER_SCANLINE: u32 = 456;
+const OAM_SCAN_DOTS: u32 = 80;
+// drawing takes at least this long, plus penalties for scrolling (and later objects/window)
+const MIN_DRAWING_DOTS: u32 = 172;
+// the fetcher restarts when it reaches the window
+const WINDOW_DRAWING_PENALTY: u32 = 6;
+// the real cost depends on where the object sits relative to the bg tiles, 6 is the minimum
+const OBJECT_DRAWING_PENALTY: u32 = 6;
+const LAST_SCANLINE: u8 = 153;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PpuMode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// One 4 byte OAM entry
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OamObject {
+    y: u8,
+    x: u8,
+    tile_index: u8,
+    bg_over_obj: bool,
+    y_flip: bool,
+    x_flip: bool,
+    // false for OBP0, true for OBP1
+    palette: bool,
+    // position in OAM, breaks ties between objects at the same x
+    oam_index: u8,
+}
+
+impl OamObject {
+    fn new(bytes: &[u8], oam_index: u8) -> Self {
+        let attributes = bytes[3];
+        Self {
+            y: bytes[0],
+            x: bytes[1],
+            tile_index: bytes[2],
+            bg_over_obj: (attributes & 0b10000000) > 0,
+            y_flip: (attributes & 0b01000000) > 0,
+            x_flip: (attributes & 0b00100000) > 0,
+            palette: (attributes & 0b00010000) > 0,
+            oam_index,
+        }
+    }
+    fn to_bytes(self) -> [u8; 4] {
+        let bg_over_obj = if self.bg_over_obj { 0b10000000 } else { 0 };
+        let y_flip = if self.y_flip { 0b01000000 } else { 0 };
+        let x_flip = if self.x_flip { 0b00100000 } else { 0 };
+        let palette = if self.palette { 0b00010000 } else { 0 };
+        [
+            self.y,
+            self.x,
+            self.tile_index,
+            bg_over_obj | y_flip | x_flip | palette,
+        ]
+    }
+}
+
 pub(crate) struct GameboyRenderer {
-    pub canvas: WindowCanvas,
+    // None when running headless
+    pub canvas: Option<WindowCanvas>,
     current_scanline: u8,
     frame_elapsed_dots: u32,
     scanline_elapsed_dots: u32,
-    current_pixel: u8,
+    mode: PpuMode,
+    // how long mode 3 lasts on the current line
+    drawing_dots: u32,
+    // set when the ppu enters VBlank, cleared by whoever presents the frame
+    pub frame_ready: bool,
+    // set once LY has matched WY this frame, the window can't show up before that
+    window_y_triggered: bool,
+    // which window row gets drawn next, only advances on lines the window was actually drawn
+    window_line: u8,
+    // picked during OAM scan, drawn in mode 3
+    line_objects: Vec<OamObject>,
+    // the OR of every enabled STAT source, the interrupt only fires when this goes low to high
+    stat_line: bool,
+    // bg/window color indices for the line being drawn, before BGP.  Objects need these for
+    // priority
+    bg_line: [u8; GAMEBOY_WIDTH],
+    pub(crate) display_palette: DisplayPalette,
+    // drawn over the top right corner of the screen, e.g. the emulation speed
+    pub(crate) overlay_text: Option<String>,
+    // present waits for the display's vblank
+    pub(crate) vsync: bool,
+    // shades (0-3, after the dmg palettes) for the whole frame
     pub current_display: [u8; 23040],
 }
 
@@ -33,8 +166,8 @@ impl RendererLcdcFlags {
     pub(crate) fn new(byte: u8) -> Self {
         Self {
             lcd_enable: (byte & 0b10000000) > 0,
-            window_tile_map: (byte & 0b0100000) > 0,
-            window_enable: (byte & 0b0010000) > 0,
+            window_tile_map: (byte & 0b01000000) > 0,
+            window_enable: (byte & 0b00100000) > 0,
             bg_and_window_tiles: (byte & 0b00010000) > 0,
             bg_tile_map: (byte & 0b00001000) > 0,
             obj_size: (byte & 0b0000_0100) > 0,
@@ -44,6 +177,35 @@ impl RendererLcdcFlags {
     }
 }
 
+/// Draws `text` in the top right corner on a background box, in gameboy pixels
+fn draw_overlay_text(canvas: &mut WindowCanvas, text: &str, foreground: Color, background: Color) {
+    let glyph_count = text.chars().count() as i32;
+    let text_width = glyph_count * (FONT_GLYPH_WIDTH + 1) - 1;
+    let left = GAMEBOY_WIDTH as i32 - text_width - 2;
+    canvas.set_draw_color(background);
+    canvas
+        .fill_rect(Rect::new(
+            left - 1,
+            1,
+            (text_width + 2) as u32,
+            (FONT_GLYPH_HEIGHT + 2) as u32,
+        ))
+        .expect("Unable to draw overlay");
+    canvas.set_draw_color(foreground);
+    for (index, character) in text.chars().enumerate() {
+        let glyph_left = left + index as i32 * (FONT_GLYPH_WIDTH + 1);
+        for (row, row_bits) in font_glyph(character).iter().enumerate() {
+            for column in 0..FONT_GLYPH_WIDTH {
+                if (row_bits >> (FONT_GLYPH_WIDTH - 1 - column)) & 0b1 > 0 {
+                    canvas
+                        .draw_point(Point::new(glyph_left + column, 2 + row as i32))
+                        .expect("Unable to draw overlay");
+                }
+            }
+        }
+    }
+}
+
 pub struct SdlBackend {
     sdl_context: sdl2::Sdl,
     video_subsystem: sdl2::VideoSubsystem,
@@ -86,27 +248,87 @@ impl SdlBackend {
 }
 
 impl GameboyRenderer {
-    pub(crate) fn new(sdl_backend: &mut SdlBackend) -> Result<Self, String> {
+    /// With `vsync`, presenting waits for the display's vblank as long as the display runs at
+    /// about the gameboy's rate.  Check `vsync` on the result to see if it did
+    pub(crate) fn new(
+        sdl_backend: &mut SdlBackend,
+        scale: u32,
+        vsync: bool,
+    ) -> Result<Self, String> {
         let window = sdl_backend.get_window(WindowDetails::new(
             "Gameboy".to_owned(),
-            crate::GAMEBOY_WIDTH as u32,
-            crate::GAMEBOY_HEIGHT as u32,
+            crate::GAMEBOY_WIDTH as u32 * scale,
+            crate::GAMEBOY_HEIGHT as u32 * scale,
         ))?;
-        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        //SDL rounds the refresh rate to whole Hz
+        let refresh_rate = window.display_mode().map_or(0, |mode| mode.refresh_rate);
+        let vsync = vsync && VSYNC_REFRESH_RATES.contains(&refresh_rate);
+        debug!("Display runs at {}Hz, vsync {}", refresh_rate, vsync);
+        let mut canvas_builder = window.into_canvas();
+        if vsync {
+            canvas_builder = canvas_builder.present_vsync();
+        }
+        let mut canvas = canvas_builder.build().map_err(|e| e.to_string())?;
         canvas.set_draw_color(Color::RGB(0x64, 0x95, 0xED));
         canvas.clear();
         canvas.present();
-        Ok(Self {
+        let mut renderer = Self::with_canvas(Some(canvas));
+        renderer.vsync = vsync;
+        Ok(renderer)
+    }
+    /// A renderer that still runs the ppu but never draws anywhere
+    pub(crate) fn new_headless() -> Self {
+        Self::with_canvas(None)
+    }
+    fn with_canvas(canvas: Option<WindowCanvas>) -> Self {
+        Self {
             canvas,
             current_scanline: 0u8,
             frame_elapsed_dots: 0u32,
             scanline_elapsed_dots: 0u32,
-            current_pixel: 0u8,
+            mode: PpuMode::OamScan,
+            drawing_dots: MIN_DRAWING_DOTS,
+            frame_ready: false,
+            window_y_triggered: false,
+            window_line: 0,
+            line_objects: Vec::with_capacity(MAX_OBJECTS_PER_LINE),
+            stat_line: false,
+            bg_line: [0u8; GAMEBOY_WIDTH],
+            display_palette: DisplayPalette::new(ColorScheme::Green, None),
+            overlay_text: None,
+            vsync: false,
             current_display: [0u8; 160 * 144],
-        })
+        }
+    }
+    /// Turns waiting for vblank on present on or off, e.g. so fast-forward isn't held to the
+    /// display's rate
+    pub(crate) fn set_vsync(&mut self, vsync: bool) -> Result<(), String> {
+        let Some(canvas) = self.canvas.as_ref() else {
+            return Err("No window".to_owned());
+        };
+        //the sdl2 crate only exposes vsync when building the canvas
+        if unsafe { SDL_RenderSetVSync(canvas.raw(), vsync as c_int) } != 0 {
+            return Err(sdl2::get_error());
+        }
+        self.vsync = vsync;
+        Ok(())
+    }
+    pub fn set_title(&mut self, title: &str) {
+        let Some(canvas) = self.canvas.as_mut() else {
+            return;
+        };
+        if let Err(e) = canvas.window_mut().set_title(title) {
+            debug!("Unable to set window title: {}", e);
+        }
     }
     pub fn current_display_to_texture(&mut self) {
-        let tex_creator = self.canvas.texture_creator();
+        let Some(canvas) = self.canvas.as_mut() else {
+            return;
+        };
+        let current_display = &self.current_display;
+        let overlay_text = self.overlay_text.as_deref();
+        let colors = self.display_palette.colors();
+        let tex_creator = canvas.texture_creator();
         let mut texture = tex_creator
             .create_texture_target(
                 PixelFormatEnum::RGBA8888,
@@ -114,92 +336,1028 @@ impl GameboyRenderer {
                 GAMEBOY_HEIGHT as u32,
             )
             .expect("unable to create texture");
-        self.canvas
+        canvas
             .with_texture_canvas(&mut texture, |texture_canvas| {
-                for i in 0..self.current_display.len() {
+                for i in 0..current_display.len() {
                     let x = (i % crate::GAMEBOY_WIDTH) as i32;
                     let y = (i / crate::GAMEBOY_WIDTH) as i32;
-                    let i_val = self.current_display[i];
-                    let draw_color = match i_val {
-                        0 => Color::WHITE,
-                        1 => Color::RED,
-                        _ => Color::RED,
-                    };
+                    let draw_color = colors[(current_display[i] & 0b11) as usize];
                     texture_canvas.set_draw_color(draw_color);
                     texture_canvas
                         .draw_point(Point::new(x, y))
                         .expect("Unable to draw point");
                 }
+                if let Some(overlay_text) = overlay_text {
+                    draw_overlay_text(texture_canvas, overlay_text, colors[0], colors[3]);
+                }
             })
             .expect("Unable to draw to texture");
-        self.canvas
+        canvas
             .copy(&texture, None, None)
             .expect("Unable to copy texture to canvas");
-        self.canvas.present();
+        canvas.present();
     }
     pub fn render_current_display(&mut self) {
         self.current_display_to_texture();
     }
 
-    pub fn tick_dot(&mut self, lcdc_flags: RendererLcdcFlags, gb_memory: [u8; 0xffff + 1]) {
-        self.current_display = [1u8; 23040];
+    /// Advances the ppu by one dot.  Each of the 154 scanlines takes 456 dots: 80 in OAM scan,
+    /// 172ish drawing and the rest in HBlank.  Lines 144-153 are all VBlank
+    pub fn tick_dot(&mut self, lcdc_flags: &RendererLcdcFlags, gb_memory: &mut GbMemory) {
+        self.scanline_elapsed_dots += 1;
+        self.frame_elapsed_dots += 1;
+        match self.mode {
+            PpuMode::OamScan => {
+                if self.scanline_elapsed_dots == 1
+                    && gb_memory.memory_array[WY_LOCATION as usize] == self.current_scanline
+                {
+                    self.window_y_triggered = true;
+                }
+                if self.scanline_elapsed_dots == OAM_SCAN_DOTS {
+                    self.scan_oam(lcdc_flags, &gb_memory.memory_array);
+                    //the fifo has to throw away SCX % 8 pixels at the start of the line
+                    let scx = gb_memory.memory_array[SCX_LOCATION as usize];
+                    self.drawing_dots = MIN_DRAWING_DOTS + (scx % 8) as u32;
+                    if self.window_visible(lcdc_flags, &gb_memory.memory_array) {
+                        self.drawing_dots += WINDOW_DRAWING_PENALTY;
+                    }
+                    self.drawing_dots += self.line_objects.len() as u32 * OBJECT_DRAWING_PENALTY;
+                    self.mode = PpuMode::Drawing;
+                }
+            }
+            PpuMode::Drawing => {
+                if self.scanline_elapsed_dots == OAM_SCAN_DOTS + self.drawing_dots {
+                    self.render_scanline(lcdc_flags, gb_memory);
+                    self.mode = PpuMode::HBlank;
+                }
+            }
+            PpuMode::HBlank | PpuMode::VBlank => {}
+        }
+        if self.scanline_elapsed_dots == DOTS_PER_SCANLINE {
+            self.scanline_elapsed_dots = 0;
+            self.advance_scanline();
+            gb_memory.memory_array[LY_LOCATION as usize] = self.current_scanline;
+            if self.current_scanline == GAMEBOY_HEIGHT as u8 {
+                self.mode = PpuMode::VBlank;
+                self.frame_ready = true;
+                let mut i_f = gb_memory.read_interrupt_flags();
+                i_f.v_blank = true;
+                gb_memory.set_interrupt_flags(i_f);
+            } else if self.current_scanline == 0 {
+                self.frame_elapsed_dots = 0;
+                self.window_y_triggered = false;
+                self.window_line = 0;
+                self.mode = PpuMode::OamScan;
+            } else if self.mode != PpuMode::VBlank {
+                self.mode = PpuMode::OamScan;
+            }
+        }
+        self.update_stat(gb_memory);
+    }
+    /// With the LCD off the ppu sits at the start of line 0 doing nothing
+    pub fn disable_lcd(&mut self, gb_memory: &mut GbMemory) {
+        if self.mode == PpuMode::HBlank
+            && self.current_scanline == 0
+            && self.scanline_elapsed_dots == 0
+        {
+            return;
+        }
+        debug!("lcd disabled");
+        self.current_scanline = 0;
+        self.scanline_elapsed_dots = 0;
+        self.frame_elapsed_dots = 0;
+        self.window_y_triggered = false;
+        self.window_line = 0;
+        //comes back on in OAM scan of line 0, see enable_lcd
+        self.mode = PpuMode::HBlank;
+        self.stat_line = false;
+        gb_memory.memory_array[LY_LOCATION as usize] = 0;
+        //STAT reads mode 0 while the LCD is off
+        gb_memory.memory_array[STAT_LOCATION as usize] &= !0b11;
+    }
+    pub fn enable_lcd(&mut self) {
+        if self.mode == PpuMode::HBlank
+            && self.current_scanline == 0
+            && self.scanline_elapsed_dots == 0
+        {
+            debug!("lcd enabled");
+            self.mode = PpuMode::OamScan;
+        }
+    }
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u8(self.current_scanline);
+        writer.put_u32(self.frame_elapsed_dots);
+        writer.put_u32(self.scanline_elapsed_dots);
+        writer.put_u8(self.mode as u8);
+        writer.put_u32(self.drawing_dots);
+        writer.put_bool(self.frame_ready);
+        writer.put_bool(self.window_y_triggered);
+        writer.put_u8(self.window_line);
+        writer.put_u8(self.line_objects.len() as u8);
+        for object in &self.line_objects {
+            writer.put_bytes(&object.to_bytes());
+            writer.put_u8(object.oam_index);
+        }
+        writer.put_bool(self.stat_line);
+        writer.put_bytes(&self.current_display);
+    }
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let current_scanline = reader.u8()?;
+        let frame_elapsed_dots = reader.u32()?;
+        let scanline_elapsed_dots = reader.u32()?;
+        if current_scanline > LAST_SCANLINE || scanline_elapsed_dots >= DOTS_PER_SCANLINE {
+            return Err(SaveStateError::Corrupt(format!(
+                "ppu at dot {} of line {}",
+                scanline_elapsed_dots, current_scanline
+            )));
+        }
+        self.current_scanline = current_scanline;
+        self.frame_elapsed_dots = frame_elapsed_dots;
+        self.scanline_elapsed_dots = scanline_elapsed_dots;
+        self.mode = match reader.u8()? {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OamScan,
+            3 => PpuMode::Drawing,
+            other => return Err(SaveStateError::Corrupt(format!("ppu mode {}", other))),
+        };
+        self.drawing_dots = reader.u32()?;
+        self.frame_ready = reader.bool()?;
+        self.window_y_triggered = reader.bool()?;
+        self.window_line = reader.u8()?;
+        let object_count = reader.u8()? as usize;
+        if object_count > MAX_OBJECTS_PER_LINE {
+            return Err(SaveStateError::Corrupt(format!(
+                "{} objects on one line",
+                object_count
+            )));
+        }
+        self.line_objects.clear();
+        for _ in 0..object_count {
+            let bytes = reader.bytes(4)?;
+            let oam_index = reader.u8()?;
+            self.line_objects.push(OamObject::new(bytes, oam_index));
+        }
+        self.stat_line = reader.bool()?;
+        self.current_display
+            .copy_from_slice(reader.bytes(GAMEBOY_WIDTH * GAMEBOY_HEIGHT)?);
+        Ok(())
+    }
+    /// Writes the mode and LY==LYC bits into STAT and raises the LCD interrupt if any enabled
+    /// source just came on.  Sources that are already on block the others from firing again
+    fn update_stat(&mut self, gb_memory: &mut GbMemory) {
+        let stat = gb_memory.memory_array[STAT_LOCATION as usize];
+        let ly_equals_lyc = self.current_scanline == gb_memory.memory_array[LYC_LOCATION as usize];
+        let coincidence = if ly_equals_lyc { 0b100 } else { 0 };
+        gb_memory.memory_array[STAT_LOCATION as usize] =
+            0b10000000 | (stat & 0b01111000) | coincidence | self.mode as u8;
+
+        let stat_line = (ly_equals_lyc && (stat & 0b01000000) > 0)
+            || match self.mode {
+                PpuMode::HBlank => (stat & 0b00001000) > 0,
+                PpuMode::VBlank => (stat & 0b00010000) > 0,
+                PpuMode::OamScan => (stat & 0b00100000) > 0,
+                PpuMode::Drawing => false,
+            };
+        if stat_line && !self.stat_line {
+            let mut i_f = gb_memory.read_interrupt_flags();
+            i_f.lcd = true;
+            gb_memory.set_interrupt_flags(i_f);
+        }
+        self.stat_line = stat_line;
     }
     pub fn advance_scanline(&mut self) {
         let current_scanline = self.current_scanline;
         let mut new_scanline = current_scanline.saturating_add(1);
-        if new_scanline >= 153 {
+        if new_scanline > LAST_SCANLINE {
             new_scanline = 0;
         }
         self.current_scanline = new_scanline
     }
-    pub fn render_next_scanline(
-        &mut self,
-        lcdc_flags: RendererLcdcFlags,
-        gb_memory: [u8; 0x0FFFF + 1],
-    ) {
+    fn render_scanline(&mut self, lcdc_flags: &RendererLcdcFlags, gb_memory: &GbMemory) {
         let current_scanline = self.current_scanline;
         match current_scanline {
-            0..143 => {}
-            143..153 => {}
-            _ => panic!("Unexpected scanline number {current_scanline}"),
+            0..144 => {
+                self.render_bg(lcdc_flags, &gb_memory.memory_array);
+                self.render_window(lcdc_flags, &gb_memory.memory_array);
+                let bgp = gb_memory.memory_array[BGP_LOCATION as usize];
+                let line_start = current_scanline as usize * GAMEBOY_WIDTH;
+                let line = &mut self.current_display[line_start..line_start + GAMEBOY_WIDTH];
+                for (pixel, color_index) in line.iter_mut().zip(self.bg_line.iter()) {
+                    *pixel = Self::apply_palette(bgp, *color_index);
+                }
+                self.render_objects(lcdc_flags, &gb_memory.memory_array);
+            }
+            _ => panic!("Unexpected scanline number {current_scanline} while drawing"),
         }
-        self.advance_scanline();
     }
-
-    pub fn construct_pixel_array(
-        &self,
-        lcdc_flags: RendererLcdcFlags,
-        gb_memory: [u8; 0xffff + 1],
-    ) -> [u8; 256 * 256] {
-        //TODO: Actually implement!
-        [1u8; 256 * 256]
+    /// Address of row `row` of tile `tile_index`.  LCDC bit 4 picks between 0x8000 with an
+    /// unsigned index and 0x9000 with a signed one
+    fn tile_row_address(lcdc_flags: &RendererLcdcFlags, tile_index: u8, row: u8) -> usize {
+        let tile_address = if lcdc_flags.bg_and_window_tiles {
+            0x8000 + tile_index as usize * 16
+        } else {
+            (0x9000i32 + (tile_index as i8) as i32 * 16) as usize
+        };
+        tile_address + row as usize * 2
     }
-
-    pub(crate) fn render_bg(&mut self, lcdc_flags: RendererLcdcFlags, gb_memory: [u8; 0xFFFF + 1]) {
-        if !lcdc_flags.lcd_enable {
-            //Return early, screen is disabled
-            return ();
+    /// Maps a color index to a shade through one of BGP/OBP0/OBP1
+    fn apply_palette(palette: u8, color_index: u8) -> u8 {
+        (palette >> (color_index * 2)) & 0b11
+    }
+    /// Color index (0-3) of pixel `column` (0 is leftmost) in a row of 2bpp tile data
+    fn tile_pixel(gb_memory: &[u8; 0xFFFF + 1], row_address: usize, column: u8) -> u8 {
+        let low = gb_memory[row_address];
+        let high = gb_memory[row_address + 1];
+        let bit = 7 - column;
+        (((high >> bit) & 0b1) << 1) | ((low >> bit) & 0b1)
+    }
+    pub(crate) fn render_bg(
+        &mut self,
+        lcdc_flags: &RendererLcdcFlags,
+        gb_memory: &[u8; 0xFFFF + 1],
+    ) {
+        let line = &mut self.bg_line;
+        if !lcdc_flags.bg_and_window_enable_priority {
+            //on dmg this blanks the background (and window) to color 0
+            line.fill(0);
+            return;
         }
-        // go thru tilemap
-        // then go thru tiles
-        // render that shit
         let tilemap_base_location = if !lcdc_flags.bg_tile_map {
             0x9800
         } else {
             0x9c00
         };
-        let tile_data = if !lcdc_flags.bg_and_window_tiles {
-            &gb_memory[0x8000..0x8FFF]
+        let scy = gb_memory[SCY_LOCATION as usize];
+        let scx = gb_memory[SCX_LOCATION as usize];
+        //the 256x256 background wraps around in both directions
+        let bg_y = scy.wrapping_add(self.current_scanline);
+        let tilemap_row = tilemap_base_location + (bg_y as usize / 8) * 32;
+        for (x, pixel) in line.iter_mut().enumerate() {
+            let bg_x = scx.wrapping_add(x as u8);
+            let tile_index = gb_memory[tilemap_row + bg_x as usize / 8];
+            let row_address = Self::tile_row_address(lcdc_flags, tile_index, bg_y % 8);
+            *pixel = Self::tile_pixel(gb_memory, row_address, bg_x % 8);
+        }
+    }
+    /// Whether any of the window lands on the current line
+    fn window_visible(&self, lcdc_flags: &RendererLcdcFlags, gb_memory: &[u8; 0xFFFF + 1]) -> bool {
+        //on dmg bit 0 turns off the window along with the background
+        lcdc_flags.window_enable
+            && lcdc_flags.bg_and_window_enable_priority
+            && self.window_y_triggered
+            && gb_memory[WX_LOCATION as usize] < GAMEBOY_WIDTH as u8 + WX_OFFSET
+    }
+    pub(crate) fn render_window(
+        &mut self,
+        lcdc_flags: &RendererLcdcFlags,
+        gb_memory: &[u8; 0xFFFF + 1],
+    ) {
+        if !self.window_visible(lcdc_flags, gb_memory) {
+            return;
+        }
+        let tilemap_base_location = if !lcdc_flags.window_tile_map {
+            0x9800
         } else {
-            &gb_memory[0x8800..0x97FF]
+            0x9c00
         };
-        // println!("{:?}", tile_data);
-        // for i in (0..tile_data.len()).step_by(16) {
-        //
-        // }
-
-        //  start location -> 1023
-        let tilemap_data = &gb_memory[tilemap_base_location..tilemap_base_location + 1023];
-        // println!("{:?}", tilemap_data);
+        let wx = gb_memory[WX_LOCATION as usize];
+        let window_y = self.window_line;
+        let tilemap_row = tilemap_base_location + (window_y as usize / 8) * 32;
+        let line = &mut self.bg_line;
+        //WX below 7 pushes the left edge of the window off screen
+        let first_x = wx.saturating_sub(WX_OFFSET) as usize;
+        for (x, pixel) in line.iter_mut().enumerate().skip(first_x) {
+            let window_x = (x + WX_OFFSET as usize - wx as usize) as u8;
+            let tile_index = gb_memory[tilemap_row + window_x as usize / 8];
+            let row_address = Self::tile_row_address(lcdc_flags, tile_index, window_y % 8);
+            *pixel = Self::tile_pixel(gb_memory, row_address, window_x % 8);
+        }
+        self.window_line = self.window_line.wrapping_add(1);
+    }
+    fn object_height(lcdc_flags: &RendererLcdcFlags) -> i16 {
+        if lcdc_flags.obj_size { 16 } else { 8 }
+    }
+    /// Picks the first 10 objects in OAM that overlap the current line.  Objects off the left or
+    /// right edge still count towards the 10
+    fn scan_oam(&mut self, lcdc_flags: &RendererLcdcFlags, gb_memory: &[u8; 0xFFFF + 1]) {
+        self.line_objects.clear();
+        let height = Self::object_height(lcdc_flags);
+        let line = self.current_scanline as i16;
+        for oam_index in 0..OAM_OBJECT_COUNT {
+            let entry_location = OAM_LOCATION + oam_index * 4;
+            let object = OamObject::new(
+                &gb_memory[entry_location..entry_location + 4],
+                oam_index as u8,
+            );
+            let top = object.y as i16 - OBJECT_Y_OFFSET;
+            if line >= top && line < top + height {
+                self.line_objects.push(object);
+                if self.line_objects.len() == MAX_OBJECTS_PER_LINE {
+                    break;
+                }
+            }
+        }
+        //on dmg the leftmost object wins, then the one earliest in OAM
+        self.line_objects
+            .sort_by_key(|object| (object.x, object.oam_index));
+    }
+    pub(crate) fn render_objects(
+        &mut self,
+        lcdc_flags: &RendererLcdcFlags,
+        gb_memory: &[u8; 0xFFFF + 1],
+    ) {
+        if !lcdc_flags.obj_enable {
+            return;
+        }
+        let height = Self::object_height(lcdc_flags);
+        let line_start = self.current_scanline as usize * GAMEBOY_WIDTH;
+        let line = &mut self.current_display[line_start..line_start + GAMEBOY_WIDTH];
+        let mut drawn = [false; GAMEBOY_WIDTH];
+        for object in &self.line_objects {
+            let mut row =
+                (self.current_scanline as i16 - (object.y as i16 - OBJECT_Y_OFFSET)) as u8;
+            if object.y_flip {
+                row = height as u8 - 1 - row;
+            }
+            //8x16 objects ignore bit 0 of the tile index, the second tile is the bottom half
+            let tile_index = if height == 16 {
+                (object.tile_index & 0xFE) + row / 8
+            } else {
+                object.tile_index
+            };
+            //objects always use 0x8000 addressing
+            let row_address = 0x8000 + tile_index as usize * 16 + (row % 8) as usize * 2;
+            let palette = if object.palette {
+                gb_memory[OBP1_LOCATION as usize]
+            } else {
+                gb_memory[OBP0_LOCATION as usize]
+            };
+            for column in 0..8u8 {
+                let x = object.x as i16 - OBJECT_X_OFFSET + column as i16;
+                if !(0..GAMEBOY_WIDTH as i16).contains(&x) || drawn[x as usize] {
+                    continue;
+                }
+                let tile_column = if object.x_flip { 7 - column } else { column };
+                let color_index = Self::tile_pixel(gb_memory, row_address, tile_column);
+                //color 0 is transparent, so a lower priority object can still show through
+                if color_index == 0 {
+                    continue;
+                }
+                drawn[x as usize] = true;
+                if object.bg_over_obj && self.bg_line[x as usize] != 0 {
+                    continue;
+                }
+                line[x as usize] = Self::apply_palette(palette, color_index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::Gb;
+    use crate::save_state::tests::test_machine;
+
+    const LCDC_LOCATION: usize = 0xFF40;
+    const LCDC_ON: u8 = 0b10000000;
+    const LCDC_WINDOW_MAP_9C00: u8 = 0b01000000;
+    const LCDC_WINDOW_ON: u8 = 0b00100000;
+    const LCDC_TILES_8000: u8 = 0b00010000;
+    const LCDC_BG_MAP_9C00: u8 = 0b00001000;
+    const LCDC_OBJ_8X16: u8 = 0b00000100;
+    const LCDC_OBJ_ON: u8 = 0b00000010;
+    const LCDC_BG_ON: u8 = 0b00000001;
+    // every color index maps to the shade with the same number
+    const IDENTITY_PALETTE: u8 = 0b11100100;
+
+    /// A headless machine at the start of line 0 with the LCD on and identity palettes
+    fn lcd_on(lcdc: u8) -> Gb {
+        let mut gb = test_machine(None);
+        let memory = &mut gb.gb_memory.memory_array;
+        memory[LCDC_LOCATION] = LCDC_ON | lcdc;
+        memory[BGP_LOCATION as usize] = IDENTITY_PALETTE;
+        memory[OBP0_LOCATION as usize] = IDENTITY_PALETTE;
+        memory[OBP1_LOCATION as usize] = IDENTITY_PALETTE;
+        gb
+    }
+
+    fn run_lines(gb: &mut Gb, lines: u32) {
+        gb.tick_renderer(lines * DOTS_PER_SCANLINE);
+    }
+
+    fn ly(gb: &Gb) -> u8 {
+        gb.gb_memory.memory_array[LY_LOCATION as usize]
+    }
+
+    fn display_line(gb: &Gb, line: usize) -> &[u8] {
+        &gb.renderer.current_display[line * GAMEBOY_WIDTH..(line + 1) * GAMEBOY_WIDTH]
+    }
+
+    /// Fills every pixel of the tile at `address` with `color_index`
+    fn fill_tile(gb: &mut Gb, address: usize, color_index: u8) {
+        let low = if color_index & 0b01 > 0 { 0xFF } else { 0x00 };
+        let high = if color_index & 0b10 > 0 { 0xFF } else { 0x00 };
+        for row in 0..8 {
+            gb.gb_memory.memory_array[address + row * 2] = low;
+            gb.gb_memory.memory_array[address + row * 2 + 1] = high;
+        }
+    }
+
+    /// The modes the next scanline goes through and how many dots each lasts
+    fn line_modes(gb: &mut Gb) -> Vec<(PpuMode, u32)> {
+        let mut modes: Vec<(PpuMode, u32)> = Vec::new();
+        for _ in 0..DOTS_PER_SCANLINE {
+            let mode = gb.renderer.mode;
+            match modes.last_mut() {
+                Some((last, dots)) if *last == mode => *dots += 1,
+                _ => modes.push((mode, 1)),
+            }
+            gb.tick_renderer(1);
+        }
+        modes
+    }
+
+    #[test]
+    fn visible_line_mode_timing() {
+        let mut gb = lcd_on(LCDC_BG_ON);
+        for line in 0..GAMEBOY_HEIGHT as u8 {
+            assert_eq!(ly(&gb), line);
+            assert_eq!(
+                line_modes(&mut gb),
+                [
+                    (PpuMode::OamScan, 80),
+                    (PpuMode::Drawing, 172),
+                    (PpuMode::HBlank, 204)
+                ],
+                "line {}",
+                line
+            );
+        }
+        assert_eq!(ly(&gb), 144);
+    }
+
+    #[test]
+    fn scx_lengthens_drawing() {
+        let mut gb = lcd_on(LCDC_BG_ON);
+        gb.gb_memory.memory_array[SCX_LOCATION as usize] = 3;
+        assert_eq!(
+            line_modes(&mut gb),
+            [
+                (PpuMode::OamScan, 80),
+                (PpuMode::Drawing, 175),
+                (PpuMode::HBlank, 201)
+            ]
+        );
+        //only the fine scroll costs anything
+        gb.gb_memory.memory_array[SCX_LOCATION as usize] = 8;
+        assert_eq!(
+            line_modes(&mut gb),
+            [
+                (PpuMode::OamScan, 80),
+                (PpuMode::Drawing, 172),
+                (PpuMode::HBlank, 204)
+            ]
+        );
+    }
+
+    #[test]
+    fn vblank_lines_and_frame_length() {
+        let mut gb = lcd_on(LCDC_BG_ON);
+        run_lines(&mut gb, GAMEBOY_HEIGHT as u32);
+        for line in GAMEBOY_HEIGHT as u8..=LAST_SCANLINE {
+            assert_eq!(ly(&gb), line);
+            assert_eq!(
+                line_modes(&mut gb),
+                [(PpuMode::VBlank, 456)],
+                "line {}",
+                line
+            );
+        }
+        assert_eq!(ly(&gb), 0);
+        assert_eq!(gb.renderer.mode, PpuMode::OamScan);
+
+        //154 lines of 456 dots
+        gb.tick_renderer(70223);
+        assert_eq!(ly(&gb), LAST_SCANLINE);
+        gb.tick_renderer(1);
+        assert_eq!(ly(&gb), 0);
+    }
+
+    #[test]
+    fn lcd_off_parks_at_line_0() {
+        let mut gb = lcd_on(LCDC_BG_ON);
+        run_lines(&mut gb, 10);
+        gb.tick_renderer(100);
+        gb.gb_memory.memory_array[LCDC_LOCATION] = LCDC_BG_ON;
+        gb.tick_renderer(1000);
+        assert_eq!(ly(&gb), 0);
+        assert_eq!(gb.gb_memory.memory_array[STAT_LOCATION as usize] & 0b11, 0);
+
+        gb.gb_memory.memory_array[LCDC_LOCATION] = LCDC_ON | LCDC_BG_ON;
+        //the ppu only sees LCDC when it next runs
+        gb.tick_renderer(0);
+        assert_eq!(
+            line_modes(&mut gb),
+            [
+                (PpuMode::OamScan, 80),
+                (PpuMode::Drawing, 172),
+                (PpuMode::HBlank, 204)
+            ]
+        );
+        assert_eq!(ly(&gb), 1);
+    }
+
+    #[test]
+    fn bg_tile_data_addressing() {
+        let mut gb = lcd_on(LCDC_BG_ON | LCDC_TILES_8000);
+        //tile 1 at 0x8010 or 0x9010, tile 0x80 at 0x8800 either way
+        fill_tile(&mut gb, 0x8010, 1);
+        fill_tile(&mut gb, 0x9010, 2);
+        fill_tile(&mut gb, 0x8800, 3);
+        gb.gb_memory.memory_array[0x9800] = 0x01;
+        gb.gb_memory.memory_array[0x9801] = 0x80;
+        run_lines(&mut gb, 1);
+        assert_eq!(
+            display_line(&gb, 0)[..17],
+            [1, 1, 1, 1, 1, 1, 1, 1, 3, 3, 3, 3, 3, 3, 3, 3, 0]
+        );
+
+        gb.gb_memory.memory_array[LCDC_LOCATION] = LCDC_ON | LCDC_BG_ON;
+        run_lines(&mut gb, 1);
+        assert_eq!(
+            display_line(&gb, 1)[..17],
+            [2, 2, 2, 2, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 3, 3, 0]
+        );
+    }
+
+    #[test]
+    fn bg_tile_map_select() {
+        let mut gb = lcd_on(LCDC_BG_ON | LCDC_TILES_8000);
+        fill_tile(&mut gb, 0x8010, 1);
+        fill_tile(&mut gb, 0x8020, 2);
+        gb.gb_memory.memory_array[0x9800] = 1;
+        gb.gb_memory.memory_array[0x9C00] = 2;
+        run_lines(&mut gb, 1);
+        assert_eq!(display_line(&gb, 0)[0], 1);
+
+        gb.gb_memory.memory_array[LCDC_LOCATION] =
+            LCDC_ON | LCDC_BG_ON | LCDC_TILES_8000 | LCDC_BG_MAP_9C00;
+        run_lines(&mut gb, 1);
+        assert_eq!(display_line(&gb, 1)[0], 2);
+    }
+
+    #[test]
+    fn bg_scrolls_and_wraps() {
+        let mut gb = lcd_on(LCDC_BG_ON | LCDC_TILES_8000);
+        fill_tile(&mut gb, 0x8010, 1);
+        fill_tile(&mut gb, 0x8020, 2);
+        fill_tile(&mut gb, 0x8030, 3);
+        let memory = &mut gb.gb_memory.memory_array;
+        //row 0 starts 1 2 and ends in 3, row 1 starts with 2
+        memory[0x9800] = 1;
+        memory[0x9801] = 2;
+        memory[0x981F] = 3;
+        memory[0x9820] = 2;
+        memory[SCX_LOCATION as usize] = 4;
+        run_lines(&mut gb, 1);
+        let line = display_line(&gb, 0);
+        assert_eq!(line[..4], [1; 4]);
+        assert_eq!(line[4..12], [2; 8]);
+        assert_eq!(line[12], 0);
+
+        //the left edge wraps around to column 31
+        gb.gb_memory.memory_array[SCX_LOCATION as usize] = 252;
+        run_lines(&mut gb, 1);
+        let line = display_line(&gb, 1);
+        assert_eq!(line[..4], [3; 4]);
+        assert_eq!(line[4..12], [1; 8]);
+
+        //line 2 with SCY 6 is bg row 8, the second row of tiles
+        gb.gb_memory.memory_array[SCX_LOCATION as usize] = 0;
+        gb.gb_memory.memory_array[SCY_LOCATION as usize] = 6;
+        run_lines(&mut gb, 1);
+        assert_eq!(display_line(&gb, 2)[..9], [2, 2, 2, 2, 2, 2, 2, 2, 0]);
+
+        //and SCY 254 on line 3 wraps back to row 1 of the first row of tiles
+        gb.gb_memory.memory_array[SCY_LOCATION as usize] = 254;
+        run_lines(&mut gb, 1);
+        assert_eq!(display_line(&gb, 3)[..9], [1, 1, 1, 1, 1, 1, 1, 1, 2]);
+    }
+
+    #[test]
+    fn bgp_and_bg_enable() {
+        let mut gb = lcd_on(LCDC_BG_ON | LCDC_TILES_8000);
+        fill_tile(&mut gb, 0x8010, 1);
+        gb.gb_memory.memory_array[0x9800] = 1;
+        gb.gb_memory.memory_array[BGP_LOCATION as usize] = 0b00011011;
+        run_lines(&mut gb, 1);
+        assert_eq!(display_line(&gb, 0)[..9], [2, 2, 2, 2, 2, 2, 2, 2, 3]);
+
+        //with bit 0 off everything is color 0, which still goes through BGP
+        gb.gb_memory.memory_array[LCDC_LOCATION] = LCDC_ON | LCDC_TILES_8000;
+        run_lines(&mut gb, 1);
+        assert_eq!(display_line(&gb, 1)[..9], [3; 9]);
+    }
+
+    /// Window on with its map at 0x9C00, tile 2 everywhere.  Row 2 of tile 2 is color 3 and
+    /// the rest color 1, so which window row got drawn shows up on screen.  BG is all color 0
+    fn window_machine(wy: u8, wx: u8) -> Gb {
+        let mut gb = lcd_on(LCDC_BG_ON | LCDC_TILES_8000 | LCDC_WINDOW_ON | LCDC_WINDOW_MAP_9C00);
+        fill_tile(&mut gb, 0x8020, 1);
+        let memory = &mut gb.gb_memory.memory_array;
+        memory[0x8024] = 0xFF;
+        memory[0x8025] = 0xFF;
+        memory[0x9C00..0x9C20].fill(2);
+        memory[WY_LOCATION as usize] = wy;
+        memory[WX_LOCATION as usize] = wx;
+        gb
+    }
+
+    #[test]
+    fn window_position() {
+        let mut gb = window_machine(2, 17);
+        run_lines(&mut gb, 2);
+        assert_eq!(display_line(&gb, 1), [0; GAMEBOY_WIDTH]);
+        //the fetcher restart costs 6 dots
+        assert_eq!(
+            line_modes(&mut gb),
+            [
+                (PpuMode::OamScan, 80),
+                (PpuMode::Drawing, 178),
+                (PpuMode::HBlank, 198)
+            ]
+        );
+        let line = display_line(&gb, 2);
+        assert_eq!(line[..10], [0; 10]);
+        assert_eq!(line[10..], [1; GAMEBOY_WIDTH - 10]);
+
+        //WX under 7 cuts off the left of the window
+        gb.gb_memory.memory_array[0x9C01] = 3;
+        fill_tile(&mut gb, 0x8030, 2);
+        gb.gb_memory.memory_array[WX_LOCATION as usize] = 3;
+        run_lines(&mut gb, 1);
+        let line = display_line(&gb, 3);
+        assert_eq!(line[..4], [1; 4]);
+        assert_eq!(line[4..12], [2; 8]);
+    }
+
+    #[test]
+    fn window_line_counter_skips_lines_without_window() {
+        let mut gb = window_machine(0, 7);
+        run_lines(&mut gb, 2);
+        assert_eq!(gb.renderer.window_line, 2);
+
+        //moved off the right edge the window isn't drawn and its row doesn't advance
+        gb.gb_memory.memory_array[WX_LOCATION as usize] = 167;
+        run_lines(&mut gb, 2);
+        assert_eq!(gb.renderer.window_line, 2);
+        assert_eq!(display_line(&gb, 3), [0; GAMEBOY_WIDTH]);
+
+        //coming back it picks up at row 2, not row 4
+        gb.gb_memory.memory_array[WX_LOCATION as usize] = 7;
+        run_lines(&mut gb, 1);
+        assert_eq!(display_line(&gb, 4), [3; GAMEBOY_WIDTH]);
+        assert_eq!(gb.renderer.window_line, 3);
+
+        //same for turning the window off
+        gb.gb_memory.memory_array[LCDC_LOCATION] &= !LCDC_WINDOW_ON;
+        run_lines(&mut gb, 1);
+        assert_eq!(gb.renderer.window_line, 3);
+
+        //and the counter starts over next frame
+        gb.gb_memory.memory_array[LCDC_LOCATION] |= LCDC_WINDOW_ON;
+        run_lines(&mut gb, LAST_SCANLINE as u32 - 5);
+        assert_eq!(ly(&gb), 0);
+        assert_eq!(gb.renderer.window_line, 0);
+        run_lines(&mut gb, 3);
+        assert_eq!(display_line(&gb, 2), [3; GAMEBOY_WIDTH]);
+    }
+
+    #[test]
+    fn window_waits_for_ly_to_match_wy() {
+        let mut gb = window_machine(100, 7);
+        run_lines(&mut gb, 50);
+        //LY has already gone past the new WY, so the window stays hidden this frame
+        gb.gb_memory.memory_array[WY_LOCATION as usize] = 10;
+        run_lines(&mut gb, GAMEBOY_HEIGHT as u32 - 50);
+        assert_eq!(gb.renderer.window_line, 0);
+        assert_eq!(display_line(&gb, 100), [0; GAMEBOY_WIDTH]);
+
+        run_lines(
+            &mut gb,
+            10 + LAST_SCANLINE as u32 + 1 - GAMEBOY_HEIGHT as u32,
+        );
+        assert_eq!(ly(&gb), 10);
+        run_lines(&mut gb, 3);
+        assert_eq!(display_line(&gb, 9), [0; GAMEBOY_WIDTH]);
+        assert_eq!(display_line(&gb, 10), [1; GAMEBOY_WIDTH]);
+        assert_eq!(display_line(&gb, 12), [3; GAMEBOY_WIDTH]);
+
+        //once triggered, moving WY further down doesn't hide it again
+        gb.gb_memory.memory_array[WY_LOCATION as usize] = 100;
+        run_lines(&mut gb, 1);
+        assert_eq!(display_line(&gb, 13), [1; GAMEBOY_WIDTH]);
+        assert_eq!(gb.renderer.window_line, 4);
+    }
+
+    const OBJ_BG_OVER_OBJ: u8 = 0b10000000;
+    const OBJ_Y_FLIP: u8 = 0b01000000;
+    const OBJ_X_FLIP: u8 = 0b00100000;
+    const OBJ_OBP1: u8 = 0b00010000;
+
+    fn put_object(gb: &mut Gb, oam_index: usize, y: u8, x: u8, tile_index: u8, attributes: u8) {
+        let location = OAM_LOCATION + oam_index * 4;
+        gb.gb_memory.memory_array[location..location + 4]
+            .copy_from_slice(&[y, x, tile_index, attributes]);
+    }
+
+    /// Objects on, tiles 1-3 filled with colors 1-3
+    fn object_machine(lcdc: u8) -> Gb {
+        let mut gb = lcd_on(LCDC_BG_ON | LCDC_TILES_8000 | LCDC_OBJ_ON | lcdc);
+        for color_index in 1..=3 {
+            fill_tile(&mut gb, 0x8000 + color_index as usize * 16, color_index);
+        }
+        gb
+    }
+
+    #[test]
+    fn ten_objects_per_line() {
+        let mut gb = object_machine(0);
+        //on another line, doesn't count
+        put_object(&mut gb, 0, 40, 50, 1, 0);
+        //off the left edge, still count
+        put_object(&mut gb, 1, 16, 0, 1, 0);
+        put_object(&mut gb, 2, 16, 0, 1, 0);
+        //later in OAM further left, so 11 and 12 are the ones that miss out
+        for oam_index in 3..=12 {
+            put_object(
+                &mut gb,
+                oam_index,
+                16,
+                8 + (12 - oam_index as u8) * 10,
+                1,
+                0,
+            );
+        }
+        //each object costs 6 dots of drawing
+        assert_eq!(
+            line_modes(&mut gb),
+            [
+                (PpuMode::OamScan, 80),
+                (PpuMode::Drawing, 232),
+                (PpuMode::HBlank, 144)
+            ]
+        );
+        let picked: Vec<(u8, u8)> = gb
+            .renderer
+            .line_objects
+            .iter()
+            .map(|object| (object.x, object.oam_index))
+            .collect();
+        assert_eq!(
+            picked,
+            [
+                (0, 1),
+                (0, 2),
+                (28, 10),
+                (38, 9),
+                (48, 8),
+                (58, 7),
+                (68, 6),
+                (78, 5),
+                (88, 4),
+                (98, 3)
+            ]
+        );
+        let line = display_line(&gb, 0);
+        assert_eq!(line[..20], [0; 20]);
+        assert_eq!(line[20..28], [1; 8]);
+    }
+
+    #[test]
+    fn leftmost_object_wins() {
+        let mut gb = object_machine(0);
+        //index 1 is further left so it wins the overlap despite coming later in OAM
+        put_object(&mut gb, 0, 16, 24, 2, 0);
+        put_object(&mut gb, 1, 16, 20, 1, 0);
+        //at the same x the earlier one wins
+        put_object(&mut gb, 2, 16, 60, 2, 0);
+        put_object(&mut gb, 3, 16, 60, 1, 0);
+        run_lines(&mut gb, 1);
+        let line = display_line(&gb, 0);
+        assert_eq!(line[12..20], [1; 8]);
+        assert_eq!(line[20..24], [2; 4]);
+        assert_eq!(line[52..60], [2; 8]);
+
+        //the winner's color 0 pixels let the loser show through
+        let memory = &mut gb.gb_memory.memory_array;
+        memory[0x8040..0x8050].copy_from_slice(&[0x0F, 0x00].repeat(8));
+        put_object(&mut gb, 4, 17, 100, 4, 0);
+        put_object(&mut gb, 5, 17, 100, 2, 0);
+        run_lines(&mut gb, 1);
+        let line = display_line(&gb, 1);
+        assert_eq!(line[92..96], [2; 4]);
+        assert_eq!(line[96..100], [1; 4]);
+    }
+
+    #[test]
+    fn obj_enable() {
+        let mut gb = object_machine(0);
+        put_object(&mut gb, 0, 16, 8, 1, 0);
+        gb.gb_memory.memory_array[LCDC_LOCATION] &= !LCDC_OBJ_ON;
+        run_lines(&mut gb, 1);
+        assert_eq!(display_line(&gb, 0)[..8], [0; 8]);
+        gb.gb_memory.memory_array[LCDC_LOCATION] |= LCDC_OBJ_ON;
+        run_lines(&mut gb, 1);
+        assert_eq!(display_line(&gb, 1)[..8], [1; 8]);
+    }
+
+    #[test]
+    fn object_flips_and_palettes() {
+        let mut gb = object_machine(0);
+        //tile 4 is a single color 1 pixel in its top left corner
+        gb.gb_memory.memory_array[0x8040] = 0x80;
+        put_object(&mut gb, 0, 16, 8, 4, 0);
+        put_object(&mut gb, 1, 16, 18, 4, OBJ_X_FLIP);
+        put_object(&mut gb, 2, 16, 28, 4, OBJ_Y_FLIP);
+        put_object(&mut gb, 3, 16, 38, 4, OBJ_OBP1);
+        gb.gb_memory.memory_array[OBP1_LOCATION as usize] = 0b11111100;
+        run_lines(&mut gb, 8);
+        let top = display_line(&gb, 0);
+        assert_eq!(top[0], 1);
+        assert_eq!(top[10..18], [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(top[20], 0);
+        assert_eq!(top[30], 3);
+        assert_eq!(display_line(&gb, 7)[20], 1);
+    }
+
+    #[test]
+    fn tall_objects() {
+        let mut gb = object_machine(LCDC_OBJ_8X16);
+        //bit 0 of the tile index is ignored, tile 2 on top and 3 below
+        put_object(&mut gb, 0, 16, 8, 3, 0);
+        put_object(&mut gb, 1, 16, 18, 3, OBJ_Y_FLIP);
+        run_lines(&mut gb, 17);
+        assert_eq!(display_line(&gb, 0)[..8], [2; 8]);
+        assert_eq!(display_line(&gb, 15)[..8], [3; 8]);
+        assert_eq!(display_line(&gb, 16)[..8], [0; 8]);
+        //flipped, the bottom tile comes first
+        assert_eq!(display_line(&gb, 0)[10..18], [3; 8]);
+        assert_eq!(display_line(&gb, 15)[10..18], [2; 8]);
+    }
+
+    #[test]
+    fn bg_over_obj() {
+        let mut gb = object_machine(0);
+        //bg is color 1 for the first tile, then color 0
+        gb.gb_memory.memory_array[0x9800] = 1;
+        put_object(&mut gb, 0, 16, 12, 2, OBJ_BG_OVER_OBJ);
+        //hidden behind the bg along with the object that beat it
+        put_object(&mut gb, 1, 16, 12, 3, 0);
+        run_lines(&mut gb, 1);
+        let line = display_line(&gb, 0);
+        assert_eq!(line[4..8], [1; 4]);
+        assert_eq!(line[8..12], [2; 4]);
+    }
+
+    const STAT_HBLANK_SOURCE: u8 = 0b00001000;
+    const STAT_VBLANK_SOURCE: u8 = 0b00010000;
+    const STAT_OAM_SOURCE: u8 = 0b00100000;
+    const STAT_LYC_SOURCE: u8 = 0b01000000;
+    const STAT_COINCIDENCE: u8 = 0b00000100;
+
+    fn stat(gb: &Gb) -> u8 {
+        gb.gb_memory.memory_array[STAT_LOCATION as usize]
+    }
+
+    /// Returns and clears the LCD interrupt flag
+    fn take_lcd_interrupt(gb: &mut Gb) -> bool {
+        let mut i_f = gb.gb_memory.read_interrupt_flags();
+        let lcd = i_f.lcd;
+        i_f.lcd = false;
+        gb.gb_memory.set_interrupt_flags(i_f);
+        lcd
+    }
+
+    #[test]
+    fn stat_mode_bits() {
+        let mut gb = lcd_on(LCDC_BG_ON);
+        gb.tick_renderer(1);
+        assert_eq!(stat(&gb) & 0b11, PpuMode::OamScan as u8);
+        gb.tick_renderer(79);
+        assert_eq!(stat(&gb) & 0b11, PpuMode::Drawing as u8);
+        gb.tick_renderer(172);
+        assert_eq!(stat(&gb) & 0b11, PpuMode::HBlank as u8);
+        run_lines(&mut gb, GAMEBOY_HEIGHT as u32);
+        assert_eq!(stat(&gb) & 0b11, PpuMode::VBlank as u8);
+
+        //writes only reach the source enables, bit 7 always reads 1
+        gb.gb_memory.write_byte(STAT_LOCATION, 0b00000000);
+        assert_eq!(stat(&gb), 0b10000001);
+        gb.gb_memory.write_byte(STAT_LOCATION, 0b01111110);
+        assert_eq!(stat(&gb), 0b11111001);
+    }
+
+    #[test]
+    fn lyc_compare() {
+        let mut gb = lcd_on(LCDC_BG_ON);
+        gb.gb_memory.memory_array[LYC_LOCATION as usize] = 5;
+        run_lines(&mut gb, 4);
+        assert_eq!(stat(&gb) & STAT_COINCIDENCE, 0);
+        run_lines(&mut gb, 1);
+        //the flag comes on without the interrupt enabled
+        assert_eq!(ly(&gb), 5);
+        assert_eq!(stat(&gb) & STAT_COINCIDENCE, STAT_COINCIDENCE);
+        assert!(!take_lcd_interrupt(&mut gb));
+        run_lines(&mut gb, 1);
+        assert_eq!(stat(&gb) & STAT_COINCIDENCE, 0);
+
+        gb.gb_memory.write_byte(STAT_LOCATION, STAT_LYC_SOURCE);
+        gb.gb_memory.memory_array[LYC_LOCATION as usize] = 7;
+        run_lines(&mut gb, 1);
+        assert!(take_lcd_interrupt(&mut gb));
+        //only once per match
+        gb.tick_renderer(455);
+        assert!(!take_lcd_interrupt(&mut gb));
+
+        //changing LYC to the current line counts too
+        gb.tick_renderer(10);
+        assert_eq!(ly(&gb), 8);
+        assert!(!take_lcd_interrupt(&mut gb));
+        gb.gb_memory.memory_array[LYC_LOCATION as usize] = 8;
+        gb.tick_renderer(1);
+        assert!(take_lcd_interrupt(&mut gb));
+    }
+
+    #[test]
+    fn mode_interrupt_sources() {
+        let mut gb = lcd_on(LCDC_BG_ON);
+        gb.gb_memory.memory_array[LYC_LOCATION as usize] = 0xFF;
+        gb.gb_memory.write_byte(STAT_LOCATION, STAT_HBLANK_SOURCE);
+        gb.tick_renderer(251);
+        assert!(!take_lcd_interrupt(&mut gb));
+        gb.tick_renderer(1);
+        assert!(take_lcd_interrupt(&mut gb));
+
+        gb.gb_memory.write_byte(STAT_LOCATION, STAT_OAM_SOURCE);
+        gb.tick_renderer(203);
+        assert!(!take_lcd_interrupt(&mut gb));
+        gb.tick_renderer(1);
+        assert_eq!(ly(&gb), 1);
+        assert!(take_lcd_interrupt(&mut gb));
+
+        gb.gb_memory.write_byte(STAT_LOCATION, STAT_VBLANK_SOURCE);
+        run_lines(&mut gb, GAMEBOY_HEIGHT as u32 - 2);
+        gb.tick_renderer(455);
+        assert!(!take_lcd_interrupt(&mut gb));
+        gb.tick_renderer(1);
+        assert_eq!(ly(&gb), 144);
+        assert!(take_lcd_interrupt(&mut gb));
+        //no OAM scan or HBlank during VBlank to fire again
+        run_lines(&mut gb, 9);
+        assert!(!take_lcd_interrupt(&mut gb));
+    }
+
+    #[test]
+    fn vblank_interrupt_at_line_144() {
+        let mut gb = lcd_on(LCDC_BG_ON);
+        run_lines(&mut gb, GAMEBOY_HEIGHT as u32 - 1);
+        gb.tick_renderer(455);
+        assert!(!gb.gb_memory.read_interrupt_flags().v_blank);
+        assert!(!gb.renderer.frame_ready);
+        gb.tick_renderer(1);
+        assert!(gb.gb_memory.read_interrupt_flags().v_blank);
+        assert!(gb.renderer.frame_ready);
+        //VBlank isn't an LCD interrupt unless STAT asks for it
+        assert!(!take_lcd_interrupt(&mut gb));
+    }
+
+    #[test]
+    fn stat_blocking() {
+        let mut gb = lcd_on(LCDC_BG_ON);
+        gb.gb_memory.memory_array[LYC_LOCATION as usize] = 0xFF;
+        gb.gb_memory
+            .write_byte(STAT_LOCATION, STAT_HBLANK_SOURCE | STAT_OAM_SOURCE);
+        gb.tick_renderer(252);
+        assert!(take_lcd_interrupt(&mut gb));
+        //HBlank runs straight into OAM scan so the line never drops
+        gb.tick_renderer(204);
+        assert_eq!(ly(&gb), 1);
+        assert!(!take_lcd_interrupt(&mut gb));
+        //but it does during drawing
+        gb.tick_renderer(252);
+        assert!(take_lcd_interrupt(&mut gb));
+
+        //LYC holding the line high hides the next HBlank
+        gb.gb_memory
+            .write_byte(STAT_LOCATION, STAT_HBLANK_SOURCE | STAT_LYC_SOURCE);
+        gb.gb_memory.memory_array[LYC_LOCATION as usize] = 2;
+        gb.tick_renderer(204);
+        assert_eq!(ly(&gb), 2);
+        assert!(!take_lcd_interrupt(&mut gb));
+        gb.tick_renderer(455);
+        assert!(!take_lcd_interrupt(&mut gb));
     }
 }
@@ -0,0 +1,514 @@
+use log::info;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::cartridge::Cartridge;
+use crate::gameboy::{Gb, GbModel};
+use crate::gb_memory::GbMemory;
+use crate::joypad::Joypad;
+use crate::renderer::GameboyRenderer;
+use crate::timer::Timer;
+use crate::{GAMEBOY_HEIGHT, GAMEBOY_WIDTH};
+
+// Save states are a snapshot of the whole machine: a header to say what it is and which ROM it
+// belongs to, followed by the cpu, memory (including the cartridge), timer, joypad and ppu.
+// There's no APU yet, so there's no sound state in here.  Everything is little endian
+const SAVE_STATE_MAGIC: &[u8; 8] = b"GBSTATE\0";
+// bump whenever the layout changes, old states get refused rather than misread
+const SAVE_STATE_VERSION: u16 = 1;
+// 2 bits per pixel, 4 pixels per byte
+const THUMBNAIL_LENGTH: usize = GAMEBOY_WIDTH * GAMEBOY_HEIGHT / 4;
+
+#[derive(Debug)]
+pub(crate) enum SaveStateError {
+    Io(io::Error),
+    NotASaveState,
+    UnsupportedVersion(u16),
+    DifferentRom { title: String },
+    DifferentModel,
+    Truncated,
+    Corrupt(String),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::NotASaveState => write!(f, "Not a save state"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Save state is version {}, this build only loads version {}",
+                version, SAVE_STATE_VERSION
+            ),
+            Self::DifferentRom { title } => {
+                write!(f, "Save state is for a different ROM (\"{}\")", title)
+            }
+            Self::DifferentModel => {
+                write!(f, "Save state was made emulating a different model")
+            }
+            Self::Truncated => write!(f, "Save state is truncated"),
+            Self::Corrupt(message) => write!(f, "Save state is corrupt: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+impl From<io::Error> for SaveStateError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+    pub(crate) fn put_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+    pub(crate) fn put_bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+    pub(crate) fn put_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    pub(crate) fn put_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    pub(crate) fn put_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    /// Fixed length data, the reader has to know how long it is
+    pub(crate) fn put_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+    /// Variable length data, prefixed with its length
+    pub(crate) fn put_vec(&mut self, bytes: &[u8]) {
+        self.put_u32(bytes.len() as u32);
+        self.put_bytes(bytes);
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+    pub(crate) fn bytes(&mut self, length: usize) -> Result<&'a [u8], SaveStateError> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(SaveStateError::Truncated)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+    pub(crate) fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub(crate) fn bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(SaveStateError::Corrupt(format!(
+                "0x{:02x} isn't a bool",
+                other
+            ))),
+        }
+    }
+    pub(crate) fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+    pub(crate) fn u32(&mut self) -> Result<u32, SaveStateError> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+    pub(crate) fn u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+    pub(crate) fn vec(&mut self) -> Result<Vec<u8>, SaveStateError> {
+        let length = self.u32()? as usize;
+        Ok(self.bytes(length)?.to_vec())
+    }
+}
+
+/// Everything in front of the machine state
+pub(crate) struct SaveStateHeader {
+    pub(crate) version: u16,
+    // identifies the ROM the state belongs to
+    pub(crate) rom_hash: u64,
+    pub(crate) title: String,
+    // unix time the state was made
+    pub(crate) timestamp: u64,
+    // the screen when the state was made, as packed 2 bit shades
+    pub(crate) thumbnail: Vec<u8>,
+}
+
+impl SaveStateHeader {
+    fn read(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        match reader.bytes(SAVE_STATE_MAGIC.len()) {
+            Ok(magic) if magic == SAVE_STATE_MAGIC => (),
+            _ => return Err(SaveStateError::NotASaveState),
+        }
+        let version = reader.u16()?;
+        if version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            version,
+            rom_hash: reader.u64()?,
+            title: String::from_utf8_lossy(&reader.vec()?).into_owned(),
+            timestamp: reader.u64()?,
+            thumbnail: reader.bytes(THUMBNAIL_LENGTH)?.to_vec(),
+        })
+    }
+    fn write(&self, writer: &mut StateWriter) {
+        writer.put_bytes(SAVE_STATE_MAGIC);
+        writer.put_u16(self.version);
+        writer.put_u64(self.rom_hash);
+        writer.put_vec(self.title.as_bytes());
+        writer.put_u64(self.timestamp);
+        writer.put_bytes(&self.thumbnail);
+    }
+}
+
+/// FNV-1a over the whole ROM.  The header checksums are too easy to collide between hacks and
+/// revisions of the same game
+fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn thumbnail(current_display: &[u8]) -> Vec<u8> {
+    current_display
+        .chunks(4)
+        .map(|pixels| {
+            pixels.iter().enumerate().fold(0u8, |byte, (index, shade)| {
+                byte | ((shade & 0b11) << (index * 2))
+            })
+        })
+        .collect()
+}
+
+fn model_id(model: GbModel) -> u8 {
+    match model {
+        GbModel::Dmg0 => 0,
+        GbModel::Dmg => 1,
+        GbModel::Mgb => 2,
+        GbModel::Cgb => 3,
+    }
+}
+
+/// `game.gb` keeps its states in `game.ss1` to `game.ss9`
+pub(crate) fn slot_path(rom_path: &Path, slot: u8) -> PathBuf {
+    rom_path.with_extension(format!("ss{}", slot))
+}
+
+fn write_machine(gb: &mut Gb, writer: &mut StateWriter) {
+    let registers = &gb.registers;
+    writer.put_u16(registers.get_af());
+    writer.put_u16(registers.get_bc());
+    writer.put_u16(registers.get_de());
+    writer.put_u16(registers.get_hl());
+    writer.put_u16(registers.stack_pointer);
+    writer.put_u16(registers.program_counter);
+    writer.put_bool(gb.interrupt_master_flag);
+    writer.put_bool(gb.interrupt_enable_pending);
+    writer.put_bool(gb.halted);
+    writer.put_bool(gb.halt_bug);
+    writer.put_bool(gb.stopped);
+    writer.put_u8(model_id(gb.model));
+    writer.put_bool(gb.cgb_mode);
+    writer.put_bool(gb.double_speed);
+    writer.put_u32(gb.frame_dots);
+    gb.gb_memory.save_state(writer);
+    gb.renderer.save_state(writer);
+}
+
+fn read_machine(gb: &mut Gb, reader: &mut StateReader) -> Result<(), SaveStateError> {
+    let registers = &mut gb.registers;
+    registers.set_af(reader.u16()?);
+    registers.set_bc(reader.u16()?);
+    registers.set_de(reader.u16()?);
+    registers.set_hl(reader.u16()?);
+    registers.stack_pointer = reader.u16()?;
+    registers.program_counter = reader.u16()?;
+    gb.interrupt_master_flag = reader.bool()?;
+    gb.interrupt_enable_pending = reader.bool()?;
+    gb.halted = reader.bool()?;
+    gb.halt_bug = reader.bool()?;
+    gb.stopped = reader.bool()?;
+    if reader.u8()? != model_id(gb.model) {
+        return Err(SaveStateError::DifferentModel);
+    }
+    gb.cgb_mode = reader.bool()?;
+    gb.double_speed = reader.bool()?;
+    gb.frame_dots = reader.u32()?;
+    gb.gb_memory.load_state(reader)?;
+    gb.renderer.load_state(reader)?;
+    Ok(())
+}
+
+fn snapshot(gb: &mut Gb) -> Vec<u8> {
+    let cartridge = &gb.gb_memory.cartridge;
+    let header = SaveStateHeader {
+        version: SAVE_STATE_VERSION,
+        rom_hash: rom_hash(&cartridge.rom),
+        title: cartridge.header.title.clone(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0),
+        thumbnail: thumbnail(&gb.renderer.current_display),
+    };
+    let mut writer = StateWriter::new();
+    header.write(&mut writer);
+    write_machine(gb, &mut writer);
+    writer.bytes
+}
+
+/// Enough of a copy of the machine to load a state into: the same model, boot rom and cartridge
+/// mapper and RAM, but no ROM and no window.  Whatever was in the rest gets overwritten anyway
+fn scratch_machine(gb: &Gb) -> Gb {
+    let cartridge = &gb.gb_memory.cartridge;
+    Gb {
+        registers: gb.registers.clone(),
+        gb_memory: GbMemory {
+            memory_array: gb.gb_memory.memory_array,
+            cartridge: Cartridge {
+                header: cartridge.header.clone(),
+                rom: Vec::new(),
+                ram: cartridge.ram.clone(),
+                mapper: cartridge.mapper.clone(),
+                has_battery: cartridge.has_battery,
+                save_path: None,
+                ram_dirty: false,
+                flush_requested: false,
+            },
+            boot_rom: gb.gb_memory.boot_rom.clone(),
+            timer: Timer::new(),
+            oam_dma: None,
+            joypad: Joypad::new(),
+        },
+        interrupt_master_flag: false,
+        interrupt_enable_pending: false,
+        halted: false,
+        halt_bug: false,
+        stopped: false,
+        model: gb.model,
+        cgb_mode: gb.cgb_mode,
+        double_speed: false,
+        frame_dots: 0,
+        renderer: GameboyRenderer::new_headless(),
+    }
+}
+
+fn restore(gb: &mut Gb, bytes: &[u8]) -> Result<SaveStateHeader, SaveStateError> {
+    let mut reader = StateReader::new(bytes);
+    let header = SaveStateHeader::read(&mut reader)?;
+    if header.rom_hash != rom_hash(&gb.gb_memory.cartridge.rom) {
+        return Err(SaveStateError::DifferentRom {
+            title: header.title,
+        });
+    }
+    let machine_start = reader.position;
+    //the components load straight over themselves and bail out partway on a bad state, so try
+    //it out on a scratch machine first.  Only once that got all the way through does the real
+    //one get touched, at which point the same reads can't fail
+    read_machine(&mut scratch_machine(gb), &mut reader)?;
+    if reader.position != bytes.len() {
+        return Err(SaveStateError::Corrupt(format!(
+            "{} unexpected bytes at the end",
+            bytes.len() - reader.position
+        )));
+    }
+    reader.position = machine_start;
+    read_machine(gb, &mut reader)?;
+    Ok(header)
+}
+
+pub(crate) fn save_state(gb: &mut Gb, path: &Path) -> Result<(), SaveStateError> {
+    let bytes = snapshot(gb);
+    //write then rename, so a crash mid save doesn't take the old state with it
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, &bytes)?;
+    fs::rename(&temp_path, path)?;
+    info!("Saved state to {}", path.display());
+    Ok(())
+}
+
+/// Loads a state over the running machine.  A bad state is refused without changing anything
+pub(crate) fn load_state(gb: &mut Gb, path: &Path) -> Result<SaveStateHeader, SaveStateError> {
+    let bytes = fs::read(path)?;
+    let header = restore(gb, &bytes)?;
+    info!("Loaded state from {}", path.display());
+    Ok(header)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::cartridge_header::CartridgeHeader;
+    use crate::gb_registers::GbRegisters;
+    use crate::gb_registers_flags::GbFlagsRegister;
+
+    /// A machine with no window, running a blank 32 KiB MBC1+RAM+BATTERY cart
+    pub(crate) fn test_machine(boot_rom: Option<Vec<u8>>) -> Gb {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        //MBC1+RAM+BATTERY with one bank of RAM
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        Gb {
+            registers: GbRegisters {
+                a: 0x01,
+                b: 0x00,
+                c: 0x13,
+                d: 0x00,
+                e: 0xD8,
+                h: 0x01,
+                l: 0x4D,
+                f: GbFlagsRegister {
+                    z: true,
+                    n: false,
+                    h: true,
+                    c: true,
+                },
+                stack_pointer: 0xFFFE,
+                program_counter: 0x0100,
+            },
+            gb_memory: GbMemory {
+                memory_array: [0u8; 0xFFFF + 1],
+                cartridge: Cartridge::new(rom, header).unwrap(),
+                boot_rom,
+                timer: Timer::new(),
+                oam_dma: None,
+                joypad: Joypad::new(),
+            },
+            interrupt_master_flag: false,
+            interrupt_enable_pending: false,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
+            model: GbModel::Dmg,
+            cgb_mode: false,
+            double_speed: false,
+            frame_dots: 0,
+            renderer: GameboyRenderer::new_headless(),
+        }
+    }
+
+    /// The machine part of a state, which unlike the header doesn't change with the clock
+    fn machine_bytes(gb: &mut Gb) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        write_machine(gb, &mut writer);
+        writer.bytes
+    }
+
+    fn scribble(gb: &mut Gb) {
+        gb.registers.program_counter = 0x4321;
+        gb.registers.set_bc(0xBEEF);
+        gb.halted = true;
+        gb.frame_dots = 1234;
+        gb.gb_memory.memory_array[0xC000] = 0x42;
+        gb.gb_memory.cartridge.write_rom(0x0000, 0x0A);
+        gb.gb_memory.cartridge.write_ram(0xA000, 0x99);
+        gb.gb_memory.timer.write(crate::timer::TAC_LOCATION, 0b101);
+        gb.gb_memory.timer.tick(64);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut gb = test_machine(None);
+        scribble(&mut gb);
+        let state = snapshot(&mut gb);
+        let saved = machine_bytes(&mut gb);
+
+        let mut fresh = test_machine(None);
+        let header = restore(&mut fresh, &state).unwrap();
+        assert_eq!(header.title, "TEST");
+        assert_eq!(machine_bytes(&mut fresh), saved);
+        assert_eq!(fresh.registers.program_counter, 0x4321);
+        assert_eq!(fresh.gb_memory.read_byte(0xC000), 0x42);
+        assert_eq!(fresh.gb_memory.cartridge.read_ram(0xA000), 0x99);
+    }
+
+    #[test]
+    fn loading_doesnt_mark_ram_dirty() {
+        let mut gb = test_machine(None);
+        scribble(&mut gb);
+        let state = snapshot(&mut gb);
+
+        let mut fresh = test_machine(None);
+        restore(&mut fresh, &state).unwrap();
+        assert!(!fresh.gb_memory.cartridge.ram_dirty);
+    }
+
+    #[test]
+    fn truncated_state_leaves_machine_alone() {
+        let mut gb = test_machine(None);
+        scribble(&mut gb);
+        let state = snapshot(&mut gb);
+
+        let mut other = test_machine(None);
+        let before = machine_bytes(&mut other);
+        //cut off in the middle of the ppu, so everything before it would have loaded
+        let result = restore(&mut other, &state[..state.len() - 10]);
+        assert!(matches!(result, Err(SaveStateError::Truncated)));
+        assert_eq!(machine_bytes(&mut other), before);
+    }
+
+    #[test]
+    fn bad_state_keeps_boot_rom() {
+        //a state made without a boot rom unmaps it on load, but only if the load goes through
+        let mut gb = test_machine(None);
+        let state = snapshot(&mut gb);
+
+        let mut booting = test_machine(Some(vec![0u8; 0x100]));
+        assert!(restore(&mut booting, &state[..state.len() - 1]).is_err());
+        assert!(booting.gb_memory.boot_rom.is_some());
+        assert!(restore(&mut booting, &state).is_ok());
+        assert!(booting.gb_memory.boot_rom.is_none());
+    }
+
+    #[test]
+    fn trailing_bytes_are_refused() {
+        let mut gb = test_machine(None);
+        let mut state = snapshot(&mut gb);
+        state.push(0);
+        assert!(matches!(
+            restore(&mut gb, &state),
+            Err(SaveStateError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn other_rom_is_refused() {
+        let mut gb = test_machine(None);
+        let state = snapshot(&mut gb);
+        gb.gb_memory.cartridge.rom[0x0200] = 0xFF;
+        assert!(matches!(
+            restore(&mut gb, &state),
+            Err(SaveStateError::DifferentRom { .. })
+        ));
+    }
+}
@@ -0,0 +1,259 @@
+use log::debug;
+
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+
+pub(crate) const DIV_LOCATION: u16 = 0xFF04;
+pub(crate) const TIMA_LOCATION: u16 = 0xFF05;
+pub(crate) const TMA_LOCATION: u16 = 0xFF06;
+pub(crate) const TAC_LOCATION: u16 = 0xFF07;
+
+const T_CYCLES_PER_M_CYCLE: u16 = 4;
+
+/// DIV, TIMA, TMA and TAC.  Everything runs off a 16 bit counter that goes up every T-cycle,
+/// DIV is just its top 8 bits.  TIMA goes up whenever the counter bit picked by TAC (ANDed with
+/// the TAC enable bit) goes from 1 to 0, which is why resetting DIV or changing TAC can bump it
+pub(crate) struct Timer {
+    system_counter: u16,
+    tima: u8,
+    tma: u8,
+    // 3 bits
+    tac: u8,
+    // TIMA overflowed during the last M-cycle.  It reads as 0 until TMA gets loaded in and the
+    // interrupt is requested at the end of this one
+    overflow_pending: bool,
+    // TIMA is being reloaded from TMA this M-cycle
+    reloading: bool,
+}
+
+impl Timer {
+    pub(crate) fn new() -> Self {
+        Self {
+            system_counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow_pending: false,
+            reloading: false,
+        }
+    }
+    /// For skipping the boot rom, which leaves DIV somewhere other than 0
+    pub(crate) fn set_div(&mut self, div: u8) {
+        self.system_counter = (div as u16) << 8;
+    }
+    pub(crate) fn read(&self, address: u16) -> u8 {
+        match address {
+            DIV_LOCATION => (self.system_counter >> 8) as u8,
+            TIMA_LOCATION => self.tima,
+            TMA_LOCATION => self.tma,
+            //unused bits read as 1
+            TAC_LOCATION => 0b11111000 | self.tac,
+            _ => panic!("Timer read from 0x{:04x}", address),
+        }
+    }
+    pub(crate) fn write(&mut self, address: u16, value: u8) {
+        match address {
+            DIV_LOCATION => {
+                //any write clears the whole counter, and if the selected bit was 1 that's a
+                //falling edge
+                let signal = self.timer_signal();
+                self.system_counter = 0;
+                self.detect_falling_edge(signal);
+            }
+            TIMA_LOCATION => {
+                if self.reloading {
+                    //TMA wins
+                    return;
+                }
+                //writing during the delay cancels the reload and the interrupt
+                self.overflow_pending = false;
+                self.tima = value;
+            }
+            TMA_LOCATION => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            TAC_LOCATION => {
+                //turning the timer off or switching to a bit that's 0 can look like a falling
+                //edge too
+                let signal = self.timer_signal();
+                self.tac = value & 0b111;
+                self.detect_falling_edge(signal);
+            }
+            _ => panic!("Timer write to 0x{:04x}", address),
+        }
+    }
+    /// Runs the timer for `t_cycles` (a multiple of 4), returning true if it requested the timer
+    /// interrupt
+    pub(crate) fn tick(&mut self, t_cycles: u32) -> bool {
+        let mut interrupt = false;
+        for _ in 0..t_cycles / T_CYCLES_PER_M_CYCLE as u32 {
+            interrupt |= self.tick_m_cycle();
+        }
+        interrupt
+    }
+    fn tick_m_cycle(&mut self) -> bool {
+        self.reloading = false;
+        let mut interrupt = false;
+        if self.overflow_pending {
+            self.overflow_pending = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            interrupt = true;
+            debug!("TIMA reloaded with 0x{:02x}", self.tma);
+        }
+        let signal = self.timer_signal();
+        self.system_counter = self.system_counter.wrapping_add(T_CYCLES_PER_M_CYCLE);
+        self.detect_falling_edge(signal);
+        interrupt
+    }
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u16(self.system_counter);
+        writer.put_u8(self.tima);
+        writer.put_u8(self.tma);
+        writer.put_u8(self.tac);
+        writer.put_bool(self.overflow_pending);
+        writer.put_bool(self.reloading);
+    }
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.system_counter = reader.u16()?;
+        self.tima = reader.u8()?;
+        self.tma = reader.u8()?;
+        self.tac = reader.u8()? & 0b111;
+        self.overflow_pending = reader.bool()?;
+        self.reloading = reader.bool()?;
+        Ok(())
+    }
+    /// The counter bit TAC selects, ANDed with the enable bit
+    fn timer_signal(&self) -> bool {
+        if (self.tac & 0b100) == 0 {
+            return false;
+        }
+        let bit = match self.tac & 0b11 {
+            0b00 => 9, // 4096Hz
+            0b01 => 3, // 262144Hz
+            0b10 => 5, // 65536Hz
+            _ => 7,    // 16384Hz
+        };
+        (self.system_counter >> bit) & 0b1 > 0
+    }
+    fn detect_falling_edge(&mut self, signal_before: bool) {
+        if signal_before && !self.timer_signal() {
+            self.increment_tima();
+        }
+    }
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.overflow_pending = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enabled at the fastest rate, so TIMA goes up whenever bit 3 of the counter falls, every
+    /// 16 T-cycles
+    fn running_timer(tima: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer.write(TMA_LOCATION, 0x42);
+        timer.write(TIMA_LOCATION, tima);
+        timer.write(TAC_LOCATION, 0b101);
+        timer
+    }
+
+    #[test]
+    fn div_is_top_of_counter() {
+        let mut timer = Timer::new();
+        timer.tick(252);
+        assert_eq!(timer.read(DIV_LOCATION), 0);
+        timer.tick(4);
+        assert_eq!(timer.read(DIV_LOCATION), 1);
+        timer.set_div(0xAB);
+        assert_eq!(timer.read(DIV_LOCATION), 0xAB);
+    }
+
+    #[test]
+    fn tima_counts_at_selected_rate() {
+        let mut timer = running_timer(0);
+        timer.tick(16 * 10);
+        assert_eq!(timer.read(TIMA_LOCATION), 10);
+        //4096Hz, bit 9
+        timer.write(TAC_LOCATION, 0b100);
+        timer.write(TIMA_LOCATION, 0);
+        timer.write(DIV_LOCATION, 0);
+        timer.tick(1024 * 3);
+        assert_eq!(timer.read(TIMA_LOCATION), 3);
+        assert_eq!(timer.read(TAC_LOCATION), 0b11111100);
+    }
+
+    #[test]
+    fn div_write_glitch() {
+        let mut timer = running_timer(0);
+        //bit 3 is set from counter 8 to 15
+        timer.tick(8);
+        timer.write(DIV_LOCATION, 0x55);
+        assert_eq!(timer.read(DIV_LOCATION), 0);
+        assert_eq!(timer.read(TIMA_LOCATION), 1);
+        //with bit 3 clear resetting DIV is harmless
+        timer.write(DIV_LOCATION, 0);
+        assert_eq!(timer.read(TIMA_LOCATION), 1);
+    }
+
+    #[test]
+    fn tac_change_glitch() {
+        let mut timer = running_timer(0);
+        timer.tick(8);
+        //disabling the timer while the selected bit is 1 looks like a falling edge
+        timer.write(TAC_LOCATION, 0b001);
+        assert_eq!(timer.read(TIMA_LOCATION), 1);
+        //so does switching from bit 3 (set) to bit 9 (clear)
+        timer.write(TAC_LOCATION, 0b101);
+        timer.write(TAC_LOCATION, 0b100);
+        assert_eq!(timer.read(TIMA_LOCATION), 2);
+        //enabling it doesn't
+        timer.write(TAC_LOCATION, 0b000);
+        timer.write(TAC_LOCATION, 0b101);
+        assert_eq!(timer.read(TIMA_LOCATION), 2);
+    }
+
+    #[test]
+    fn reload_delay() {
+        let mut timer = running_timer(0xFF);
+        assert!(!timer.tick(16));
+        //TIMA sits at 0 for an M-cycle before TMA gets loaded and the interrupt goes off
+        assert_eq!(timer.read(TIMA_LOCATION), 0);
+        assert!(timer.tick(4));
+        assert_eq!(timer.read(TIMA_LOCATION), 0x42);
+        assert!(!timer.tick(4));
+    }
+
+    #[test]
+    fn tima_write_during_delay_cancels_reload() {
+        let mut timer = running_timer(0xFF);
+        timer.tick(16);
+        timer.write(TIMA_LOCATION, 0x10);
+        assert!(!timer.tick(4));
+        assert_eq!(timer.read(TIMA_LOCATION), 0x10);
+    }
+
+    #[test]
+    fn tima_write_during_reload() {
+        let mut timer = running_timer(0xFF);
+        timer.tick(20);
+        //TMA wins over a TIMA write in the reload cycle
+        timer.write(TIMA_LOCATION, 0x10);
+        assert_eq!(timer.read(TIMA_LOCATION), 0x42);
+        //but a TMA write goes straight through to TIMA
+        timer.write(TMA_LOCATION, 0x99);
+        assert_eq!(timer.read(TIMA_LOCATION), 0x99);
+        //once the reload cycle is over TIMA is writable again
+        timer.tick(4);
+        timer.write(TIMA_LOCATION, 0x10);
+        assert_eq!(timer.read(TIMA_LOCATION), 0x10);
+    }
+}